@@ -1,9 +1,21 @@
-use clap::{Parser, Subcommand};
+use crate::config::{
+    AuthSettings, BatchSettings, LimitSettings, ModelDefaultSettings, ModelSettings,
+    ServerSettings, Settings, TlsSettings,
+};
+use crate::embedder::encoding::EncodingFormat;
+use crate::embedder::InputType;
+use crate::model::{ModelDType, ModelOptions, Pooling};
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(name = "embeddy")]
 #[command(version, about = "A lightweight embeddings-only model runtime", long_about = None)]
 pub struct Cli {
+    /// Config file to read server settings from [default: config.toml in the data directory]
+    #[arg(long, global = true, env = "EMBEDDY_CONFIG")]
+    pub config: Option<PathBuf>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -20,20 +32,8 @@ pub enum Commands {
         alias: Option<String>,
     },
 
-    /// Start the HTTP API server (models loaded on-demand)
-    Serve {
-        /// Device to run on (e.g., "cpu" or "cuda:0")
-        #[arg(long, default_value = "cpu")]
-        device: String,
-
-        /// Port to listen on
-        #[arg(long, default_value = "8080")]
-        port: u16,
-
-        /// Host to bind to
-        #[arg(long, default_value = "0.0.0.0")]
-        host: String,
-    },
+    /// Start the HTTP API server (models loaded on-demand unless preloaded)
+    Serve(ServeArgs),
 
     /// Run embeddings on text input
     Run {
@@ -47,8 +47,337 @@ pub enum Commands {
         /// Device to run on (e.g., "cpu" or "cuda:0")
         #[arg(long, default_value = "cpu")]
         device: String,
+
+        /// Whether the texts are queries or documents, selecting the model's prefix; no prefix when omitted
+        #[arg(long)]
+        input_type: Option<InputType>,
+
+        /// Pooling strategy, overriding the model's default
+        #[arg(long)]
+        pooling: Option<Pooling>,
+
+        /// L2-normalize embeddings, overriding the model's default
+        #[arg(long)]
+        normalize: Option<bool>,
+
+        /// Truncate inputs to this many tokens, overriding the model's default
+        #[arg(long)]
+        max_seq_length: Option<usize>,
+
+        /// Truncate embeddings to this many dimensions (Matryoshka models), then re-normalize
+        #[arg(long)]
+        dimensions: Option<usize>,
+
+        /// Output format of the embeddings
+        #[arg(long, default_value = "float")]
+        encoding_format: EncodingFormat,
+
+        /// Float range mapped onto int8 as `min,max` [default: the model's calibrated range, then the observed range]
+        #[arg(long, value_parser = parse_range, allow_hyphen_values = true)]
+        int8_range: Option<[f32; 2]>,
     },
 
+    /// Set a model's default embedding options
+    Configure(ConfigureArgs),
+
     /// List installed models
     List,
+
+    /// Convert a model's PyTorch weights to SafeTensors
+    Convert {
+        /// Model name or alias to convert
+        model: String,
+
+        /// Remove pytorch_model.bin once the conversion has been verified
+        #[arg(long)]
+        prune: bool,
+    },
+
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Print the effective server configuration, after merging the config
+    /// file, environment variables and the given flags
+    Show(ServeArgs),
+}
+
+/// Server options. Unset flags fall back to their `EMBEDDY_*` environment
+/// variable, then to the config file, then to the built-in default.
+#[derive(Args, Debug, Default)]
+pub struct ServeArgs {
+    /// Device to run on (e.g., "cpu" or "cuda:0") [default: cpu]
+    #[arg(long, env = "EMBEDDY_DEVICE")]
+    pub device: Option<String>,
+
+    /// Port to listen on [default: 8080]
+    #[arg(long, env = "EMBEDDY_PORT")]
+    pub port: Option<u16>,
+
+    /// Host to bind to [default: 0.0.0.0]
+    #[arg(long, env = "EMBEDDY_HOST")]
+    pub host: Option<String>,
+
+    /// Number of inference worker threads [default: number of CPUs]
+    #[arg(long, env = "EMBEDDY_WORKERS")]
+    pub workers: Option<usize>,
+
+    /// Maximum number of models kept loaded at once (least recently used are evicted)
+    #[arg(long, env = "EMBEDDY_MAX_LOADED_MODELS")]
+    pub max_loaded_models: Option<usize>,
+
+    /// Memory budget in bytes for loaded models, estimated from weight sizes
+    #[arg(long, env = "EMBEDDY_MEMORY_BUDGET")]
+    pub memory_budget: Option<u64>,
+
+    /// Seconds an idle model stays loaded, negative keeps models loaded forever [default: -1]
+    #[arg(long, env = "EMBEDDY_KEEP_ALIVE", allow_negative_numbers = true)]
+    pub keep_alive: Option<i64>,
+
+    /// Model to load and warm up before accepting requests (can be specified multiple times)
+    #[arg(long, env = "EMBEDDY_PRELOAD", value_delimiter = ',')]
+    pub preload: Vec<String>,
+
+    /// Start the server even if some preloaded models fail to load; `--allow-partial=false` overrides the config file
+    #[arg(
+        long,
+        env = "EMBEDDY_ALLOW_PARTIAL",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub allow_partial: Option<bool>,
+
+    /// Pooling for models without their own (see `embeddy configure`) [default: mean]
+    #[arg(long, env = "EMBEDDY_DEFAULT_POOLING")]
+    pub default_pooling: Option<Pooling>,
+
+    /// Whether to L2-normalize embeddings of models without their own setting [default: false]
+    #[arg(long, env = "EMBEDDY_DEFAULT_NORMALIZE")]
+    pub default_normalize: Option<bool>,
+
+    /// Truncate inputs to this many tokens for models without their own limit
+    #[arg(long, env = "EMBEDDY_DEFAULT_MAX_SEQ_LENGTH")]
+    pub default_max_seq_length: Option<usize>,
+
+    /// Maximum tokens coalesced into one inference batch across concurrent requests [default: 8192]
+    #[arg(long, env = "EMBEDDY_MAX_BATCH_TOKENS")]
+    pub max_batch_tokens: Option<usize>,
+
+    /// Maximum requests coalesced into one inference batch [default: 32]
+    #[arg(long, env = "EMBEDDY_MAX_BATCH_REQUESTS")]
+    pub max_batch_requests: Option<usize>,
+
+    /// Milliseconds to wait for more requests before running a batch [default: 5]
+    #[arg(long, env = "EMBEDDY_BATCH_TIMEOUT_MS")]
+    pub batch_timeout_ms: Option<u64>,
+
+    /// Maximum number of inputs accepted in a single request [default: 1024]
+    #[arg(long, env = "EMBEDDY_MAX_INPUTS_PER_REQUEST")]
+    pub max_inputs_per_request: Option<usize>,
+
+    /// Maximum length of a single input, in characters [default: 100000]
+    #[arg(long, env = "EMBEDDY_MAX_INPUT_CHARS")]
+    pub max_input_chars: Option<usize>,
+
+    /// Maximum request body size in bytes [default: 10485760]
+    #[arg(long, env = "EMBEDDY_MAX_REQUEST_BYTES")]
+    pub max_request_bytes: Option<usize>,
+
+    /// Maximum concurrent embedding requests; further requests get 503 with Retry-After [default: 256]
+    #[arg(long, env = "EMBEDDY_MAX_IN_FLIGHT")]
+    pub max_in_flight: Option<usize>,
+
+    /// API key clients must send as a bearer token (can be specified multiple times)
+    #[arg(
+        long,
+        env = "EMBEDDY_API_KEYS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    pub api_key: Vec<String>,
+
+    /// TOML file of labelled API keys (`[[keys]]` tables with `key` and `label`)
+    #[arg(long, env = "EMBEDDY_API_KEYS_FILE")]
+    pub api_keys_file: Option<PathBuf>,
+
+    /// PEM certificate chain to serve HTTPS with (requires --tls-key)
+    #[arg(long, env = "EMBEDDY_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert
+    #[arg(long, env = "EMBEDDY_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM CA bundle; when set, clients must present a certificate signed by one of these CAs
+    #[arg(long, env = "EMBEDDY_TLS_CLIENT_CA")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Seconds to let in-flight requests finish after SIGTERM/SIGINT before exiting [default: 30]
+    #[arg(long, env = "EMBEDDY_DRAIN_TIMEOUT")]
+    pub drain_timeout: Option<u64>,
+
+    /// Origin allowed to call the API from a browser (can be specified multiple times, or `*` for any)
+    #[arg(long, env = "EMBEDDY_CORS_ORIGINS", value_delimiter = ',')]
+    pub cors_origin: Vec<String>,
+
+    /// Also serve the gRPC API on this port (requires the `grpc` feature)
+    #[arg(long, env = "EMBEDDY_GRPC_PORT")]
+    pub grpc_port: Option<u16>,
+}
+
+#[derive(Args, Debug)]
+pub struct ConfigureArgs {
+    /// Model name or alias to configure
+    pub model: String,
+
+    /// How token embeddings are pooled into one vector [default: mean]
+    #[arg(long)]
+    pub pooling: Option<Pooling>,
+
+    /// Whether to L2-normalize embeddings [default: false]
+    #[arg(long)]
+    pub normalize: Option<bool>,
+
+    /// Inputs are truncated to this many tokens
+    #[arg(long)]
+    pub max_seq_length: Option<usize>,
+
+    /// Text prepended to query inputs (e.g. "query: ") [default: the model's `query` prompt]
+    #[arg(long)]
+    pub query_prefix: Option<String>,
+
+    /// Text prepended to document inputs (e.g. "passage: ") [default: the model's `document` prompt]
+    #[arg(long)]
+    pub document_prefix: Option<String>,
+
+    /// Precision to run the model weights in [default: f32]
+    #[arg(long)]
+    pub dtype: Option<ModelDType>,
+
+    /// Embedding sizes the model supports truncating to (Matryoshka models)
+    #[arg(long, value_delimiter = ',')]
+    pub matryoshka_dims: Vec<usize>,
+
+    /// Float range `min,max` mapped onto int8 for `int8` encoding, calibrated on the model's embeddings
+    #[arg(long, value_parser = parse_range, allow_hyphen_values = true)]
+    pub int8_range: Option<[f32; 2]>,
+
+    /// Reset an option to its default (can be specified multiple times)
+    #[arg(long, value_parser = ConfigureArgs::KEYS)]
+    pub unset: Vec<String>,
+}
+
+impl ConfigureArgs {
+    const KEYS: [&'static str; 8] = [
+        "pooling",
+        "normalize",
+        "max_seq_length",
+        "query_prefix",
+        "document_prefix",
+        "dtype",
+        "matryoshka_dims",
+        "int8_range",
+    ];
+
+    /// Apply the unset keys, then the given values, to `options`.
+    pub fn apply(self, options: &mut ModelOptions) {
+        for key in &self.unset {
+            match key.as_str() {
+                "pooling" => options.pooling = None,
+                "normalize" => options.normalize = None,
+                "max_seq_length" => options.max_seq_length = None,
+                "query_prefix" => options.query_prefix = None,
+                "document_prefix" => options.document_prefix = None,
+                "dtype" => options.dtype = None,
+                "matryoshka_dims" => options.matryoshka_dims = None,
+                "int8_range" => options.int8_range = None,
+                _ => unreachable!("clap only accepts known keys"),
+            }
+        }
+
+        options.pooling = self.pooling.or(options.pooling);
+        options.normalize = self.normalize.or(options.normalize);
+        options.max_seq_length = self.max_seq_length.or(options.max_seq_length);
+        options.query_prefix = self.query_prefix.or(options.query_prefix.take());
+        options.document_prefix = self.document_prefix.or(options.document_prefix.take());
+        options.dtype = self.dtype.or(options.dtype);
+        if !self.matryoshka_dims.is_empty() {
+            options.matryoshka_dims = Some(self.matryoshka_dims);
+        }
+        options.int8_range = self.int8_range.or(options.int8_range);
+    }
+}
+
+/// Parse a `min,max` range.
+fn parse_range(value: &str) -> Result<[f32; 2], String> {
+    let parse = |part: &str| {
+        part.trim()
+            .parse::<f32>()
+            .map_err(|e| format!("{}: {}", part, e))
+    };
+    match value.split_once(',') {
+        Some((min, max)) => Ok([parse(min)?, parse(max)?]),
+        None => Err("expected `min,max`".to_string()),
+    }
+}
+
+/// `Some(values)` if any were given, so an empty list doesn't override a
+/// lower layer.
+fn non_empty(values: Vec<String>) -> Option<Vec<String>> {
+    (!values.is_empty()).then_some(values)
+}
+
+impl ServeArgs {
+    /// The settings given by flags or environment variables.
+    pub fn into_settings(self) -> Settings {
+        Settings {
+            server: ServerSettings {
+                host: self.host,
+                port: self.port,
+                device: self.device,
+                workers: self.workers,
+                drain_timeout: self.drain_timeout,
+                cors_origins: non_empty(self.cors_origin),
+                grpc_port: self.grpc_port,
+            },
+            limits: LimitSettings {
+                max_inputs_per_request: self.max_inputs_per_request,
+                max_input_chars: self.max_input_chars,
+                max_request_bytes: self.max_request_bytes,
+                max_in_flight: self.max_in_flight,
+            },
+            batching: BatchSettings {
+                max_batch_tokens: self.max_batch_tokens,
+                max_batch_requests: self.max_batch_requests,
+                batch_timeout_ms: self.batch_timeout_ms,
+            },
+            auth: AuthSettings {
+                api_keys: non_empty(self.api_key),
+                api_keys_file: self.api_keys_file,
+            },
+            tls: TlsSettings {
+                cert: self.tls_cert,
+                key: self.tls_key,
+                client_ca: self.tls_client_ca,
+            },
+            models: ModelSettings {
+                preload: non_empty(self.preload),
+                allow_partial: self.allow_partial,
+                keep_alive: self.keep_alive,
+                max_loaded_models: self.max_loaded_models,
+                memory_budget: self.memory_budget,
+            },
+            model_defaults: ModelDefaultSettings {
+                pooling: self.default_pooling,
+                normalize: self.default_normalize,
+                max_seq_length: self.default_max_seq_length,
+            },
+        }
+    }
 }
@@ -1,12 +1,16 @@
+use crate::model::Pooling;
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub data_dir: PathBuf,
     pub models_dir: PathBuf,
     pub registry_path: PathBuf,
+    /// Server settings file, `config.toml` in the data directory unless
+    /// overridden with `--config`.
+    pub config_path: PathBuf,
 }
 
 impl Config {
@@ -18,6 +22,7 @@ impl Config {
         let data_dir = project_dirs.data_dir().to_path_buf();
         let models_dir = data_dir.join("models");
         let registry_path = data_dir.join("models.toml");
+        let config_path = data_dir.join(CONFIG_FILE);
 
         std::fs::create_dir_all(&data_dir)?;
         std::fs::create_dir_all(&models_dir)?;
@@ -26,6 +31,7 @@ impl Config {
             data_dir,
             models_dir,
             registry_path,
+            config_path,
         })
     }
 
@@ -34,6 +40,7 @@ impl Config {
             let data_dir = PathBuf::from(data_dir);
             let models_dir = data_dir.join("models");
             let registry_path = data_dir.join("models.toml");
+            let config_path = data_dir.join(CONFIG_FILE);
 
             std::fs::create_dir_all(&data_dir)?;
             std::fs::create_dir_all(&models_dir)?;
@@ -42,6 +49,7 @@ impl Config {
                 data_dir,
                 models_dir,
                 registry_path,
+                config_path,
             })
         } else {
             Self::new()
@@ -54,3 +62,231 @@ impl Default for Config {
         Self::new().expect("Failed to create default config")
     }
 }
+
+pub const CONFIG_FILE: &str = "config.toml";
+pub const DEFAULT_HOST: &str = "0.0.0.0";
+pub const DEFAULT_PORT: u16 = 8080;
+pub const DEFAULT_DEVICE: &str = "cpu";
+
+/// Server settings from `config.toml`, environment variables or CLI flags.
+///
+/// Every field is optional so that layers can be merged: CLI flags override
+/// `EMBEDDY_*` environment variables, which override the config file, which
+/// overrides the built-in defaults.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub limits: LimitSettings,
+    pub batching: BatchSettings,
+    pub auth: AuthSettings,
+    pub tls: TlsSettings,
+    pub models: ModelSettings,
+    pub model_defaults: ModelDefaultSettings,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub device: Option<String>,
+    pub workers: Option<usize>,
+    /// Seconds to let in-flight requests finish on shutdown
+    pub drain_timeout: Option<u64>,
+    pub cors_origins: Option<Vec<String>>,
+    /// Port for the gRPC service (requires the `grpc` feature)
+    pub grpc_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    pub max_inputs_per_request: Option<usize>,
+    pub max_input_chars: Option<usize>,
+    pub max_request_bytes: Option<usize>,
+    pub max_in_flight: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BatchSettings {
+    pub max_batch_tokens: Option<usize>,
+    pub max_batch_requests: Option<usize>,
+    pub batch_timeout_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthSettings {
+    pub api_keys: Option<Vec<String>>,
+    pub api_keys_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsSettings {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelSettings {
+    pub preload: Option<Vec<String>>,
+    pub allow_partial: Option<bool>,
+    /// Seconds an idle model stays loaded (negative = forever)
+    pub keep_alive: Option<i64>,
+    pub max_loaded_models: Option<usize>,
+    pub memory_budget: Option<u64>,
+}
+
+/// Embedding options for every model, below the model's own options from
+/// `embeddy configure`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModelDefaultSettings {
+    pub pooling: Option<Pooling>,
+    pub normalize: Option<bool>,
+    pub max_seq_length: Option<usize>,
+}
+
+/// Build a section whose fields are taken from `$over` where set, falling
+/// back to `$base`.
+macro_rules! merge_fields {
+    ($section:ident, $base:expr, $over:expr, $($field:ident),+ $(,)?) => {
+        $section {
+            $($field: $over.$field.or($base.$field)),+
+        }
+    };
+}
+
+impl Settings {
+    /// Read settings from a TOML file. A missing file yields empty settings
+    /// unless `required` is set.
+    pub fn load(path: &Path, required: bool) -> crate::error::Result<Self> {
+        if !path.exists() {
+            if required {
+                return Err(crate::error::Error::Config(format!(
+                    "Config file not found: {:?}",
+                    path
+                )));
+            }
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        toml::from_str(&content).map_err(|e| {
+            crate::error::Error::Config(format!("Invalid config file {:?}: {}", path, e))
+        })
+    }
+
+    /// Built-in defaults, for displaying the effective configuration.
+    pub fn defaults() -> Self {
+        let options = crate::server::ServerOptions::default();
+        Self {
+            server: ServerSettings {
+                host: Some(DEFAULT_HOST.to_string()),
+                port: Some(DEFAULT_PORT),
+                device: Some(DEFAULT_DEVICE.to_string()),
+                workers: Some(options.workers),
+                drain_timeout: Some(options.drain_timeout.as_secs()),
+                cors_origins: Some(options.cors_origins),
+                grpc_port: options.grpc_port,
+            },
+            limits: LimitSettings {
+                max_inputs_per_request: Some(options.limits.max_inputs_per_request),
+                max_input_chars: Some(options.limits.max_input_chars),
+                max_request_bytes: Some(options.limits.max_request_bytes),
+                max_in_flight: Some(options.limits.max_in_flight),
+            },
+            batching: BatchSettings {
+                max_batch_tokens: Some(options.batch.max_batch_tokens),
+                max_batch_requests: Some(options.batch.max_batch_requests),
+                batch_timeout_ms: Some(options.batch.batch_timeout.as_millis() as u64),
+            },
+            auth: AuthSettings {
+                api_keys: Some(Vec::new()),
+                api_keys_file: None,
+            },
+            tls: TlsSettings::default(),
+            models: ModelSettings {
+                preload: Some(options.preload),
+                allow_partial: Some(options.allow_partial),
+                keep_alive: Some(-1),
+                max_loaded_models: options.max_loaded_models,
+                memory_budget: options.memory_budget,
+            },
+            model_defaults: ModelDefaultSettings {
+                pooling: Some(Pooling::default()),
+                normalize: Some(false),
+                max_seq_length: None,
+            },
+        }
+    }
+
+    /// Layer `over` on top of `self`.
+    pub fn merge(self, over: Settings) -> Self {
+        Self {
+            server: merge_fields!(
+                ServerSettings,
+                self.server,
+                over.server,
+                host,
+                port,
+                device,
+                workers,
+                drain_timeout,
+                cors_origins,
+                grpc_port,
+            ),
+            limits: merge_fields!(
+                LimitSettings,
+                self.limits,
+                over.limits,
+                max_inputs_per_request,
+                max_input_chars,
+                max_request_bytes,
+                max_in_flight,
+            ),
+            batching: merge_fields!(
+                BatchSettings,
+                self.batching,
+                over.batching,
+                max_batch_tokens,
+                max_batch_requests,
+                batch_timeout_ms,
+            ),
+            auth: merge_fields!(AuthSettings, self.auth, over.auth, api_keys, api_keys_file),
+            tls: merge_fields!(TlsSettings, self.tls, over.tls, cert, key, client_ca),
+            models: merge_fields!(
+                ModelSettings,
+                self.models,
+                over.models,
+                preload,
+                allow_partial,
+                keep_alive,
+                max_loaded_models,
+                memory_budget,
+            ),
+            model_defaults: merge_fields!(
+                ModelDefaultSettings,
+                self.model_defaults,
+                over.model_defaults,
+                pooling,
+                normalize,
+                max_seq_length,
+            ),
+        }
+    }
+
+    /// Copy with API keys masked, for printing.
+    pub fn redacted(mut self) -> Self {
+        if let Some(keys) = &mut self.auth.api_keys {
+            keys.iter_mut()
+                .for_each(|key| *key = "<redacted>".to_string());
+        }
+        self
+    }
+}
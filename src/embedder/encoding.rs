@@ -0,0 +1,171 @@
+use crate::error::{Error, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+/// Wire format of the returned embeddings.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum EncodingFormat {
+    /// Arrays of floats
+    #[default]
+    Float,
+    /// Base64 of little-endian f32 values
+    Base64,
+    /// Base64 of little-endian IEEE half-precision values
+    Float16,
+    /// Arrays of integers in [-128, 127], scaled from a `[min, max]` range
+    Int8,
+    /// Arrays of bytes packing one sign bit per dimension, most significant
+    /// bit first
+    Ubinary,
+}
+
+/// How a request wants its embeddings encoded. Applied after
+/// `Embedder::embed`, so it never affects batching.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Encoding {
+    #[serde(default)]
+    pub encoding_format: EncodingFormat,
+    /// `[min, max]` float range mapped onto int8. Defaults to the model's
+    /// calibrated range, then to the range of the request's own embeddings.
+    #[serde(default)]
+    pub int8_range: Option<[f32; 2]>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum EncodedEmbeddings {
+    Float(Vec<Vec<f32>>),
+    Base64(Vec<String>),
+    Int8(Vec<Vec<i8>>),
+    Binary(Vec<Vec<u8>>),
+}
+
+#[derive(Debug)]
+pub struct Encoded {
+    pub embeddings: EncodedEmbeddings,
+    /// Range used for int8 quantization, which clients need to dequantize
+    pub int8_range: Option<[f32; 2]>,
+}
+
+impl Encoding {
+    /// Reject an unusable requested range before any work is done.
+    pub fn check(&self) -> Result<()> {
+        match self.int8_range {
+            Some(range) => check_range(range),
+            None => Ok(()),
+        }
+    }
+
+    /// Encode `embeddings`, falling back to the model's `calibrated` int8
+    /// range when the request doesn't give one.
+    pub fn apply(
+        &self,
+        embeddings: Vec<Vec<f32>>,
+        calibrated: Option<[f32; 2]>,
+    ) -> Result<Encoded> {
+        let mut int8_range = None;
+        let embeddings = match self.encoding_format {
+            EncodingFormat::Float => EncodedEmbeddings::Float(embeddings),
+            EncodingFormat::Base64 => EncodedEmbeddings::Base64(
+                embeddings
+                    .iter()
+                    .map(|embedding| {
+                        let bytes: Vec<u8> =
+                            embedding.iter().flat_map(|x| x.to_le_bytes()).collect();
+                        BASE64.encode(bytes)
+                    })
+                    .collect(),
+            ),
+            EncodingFormat::Float16 => EncodedEmbeddings::Base64(
+                embeddings
+                    .iter()
+                    .map(|embedding| {
+                        let bytes: Vec<u8> = embedding
+                            .iter()
+                            .flat_map(|x| half::f16::from_f32(*x).to_le_bytes())
+                            .collect();
+                        BASE64.encode(bytes)
+                    })
+                    .collect(),
+            ),
+            EncodingFormat::Int8 => {
+                let range = match self.int8_range.or(calibrated) {
+                    Some(range) => range,
+                    None => observed_range(&embeddings),
+                };
+                check_range(range)?;
+                int8_range = Some(range);
+                EncodedEmbeddings::Int8(
+                    embeddings
+                        .iter()
+                        .map(|embedding| quantize_int8(embedding, range))
+                        .collect(),
+                )
+            }
+            EncodingFormat::Ubinary => EncodedEmbeddings::Binary(
+                embeddings
+                    .iter()
+                    .map(|embedding| pack_sign_bits(embedding))
+                    .collect(),
+            ),
+        };
+
+        Ok(Encoded {
+            embeddings,
+            int8_range,
+        })
+    }
+}
+
+fn check_range([min, max]: [f32; 2]) -> Result<()> {
+    if min.is_finite() && max.is_finite() && min < max {
+        Ok(())
+    } else {
+        Err(Error::InvalidInput(format!(
+            "Invalid int8_range [{}, {}]: min must be less than max",
+            min, max
+        )))
+    }
+}
+
+/// Smallest and largest value across all embeddings. A constant input gets
+/// a range around its value so it still quantizes.
+fn observed_range(embeddings: &[Vec<f32>]) -> [f32; 2] {
+    let (min, max) = embeddings
+        .iter()
+        .flatten()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &x| {
+            (min.min(x), max.max(x))
+        });
+    if min < max {
+        [min, max]
+    } else if min.is_finite() {
+        [min - 1.0, min + 1.0]
+    } else {
+        [-1.0, 1.0]
+    }
+}
+
+/// Map `[min, max]` linearly onto `[-128, 127]`, clamping values outside it.
+fn quantize_int8(embedding: &[f32], [min, max]: [f32; 2]) -> Vec<i8> {
+    let scale = 255.0 / (max - min);
+    embedding
+        .iter()
+        .map(|x| ((x - min) * scale - 128.0).round().clamp(-128.0, 127.0) as i8)
+        .collect()
+}
+
+fn pack_sign_bits(embedding: &[f32]) -> Vec<u8> {
+    embedding
+        .chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .filter(|(_, x)| **x > 0.0)
+                .fold(0u8, |byte, (i, _)| byte | (0x80 >> i))
+        })
+        .collect()
+}
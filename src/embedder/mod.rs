@@ -1,23 +1,68 @@
+pub mod encoding;
+
 use crate::error::{Error, Result};
-use crate::model::ModelInfo;
-use candle_core::{pickle, Device, Tensor};
+use crate::metrics;
+use crate::model::{convert, tokenizer, ModelInfo, ModelOptions, Pooling};
+use candle_core::{DType, Device, Tensor};
+use serde::Deserialize;
 use serde_json::Value;
-use std::path::Path;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use tokenizers::{PostProcessor, TruncationDirection};
+
+/// Whether inputs are search queries or the documents being searched,
+/// selecting the model's query or document prefix. Inputs without a type
+/// get no prefix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum InputType {
+    Query,
+    Document,
+}
+
+/// Per-request overrides of the model's configured defaults. Requests are
+/// only batched together when their options match.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct EmbedOptions {
+    #[serde(default)]
+    pub input_type: Option<InputType>,
+    #[serde(default)]
+    pub pooling: Option<Pooling>,
+    #[serde(default)]
+    pub normalize: Option<bool>,
+    #[serde(default)]
+    pub max_seq_length: Option<usize>,
+    /// Truncate embeddings to this many dimensions, then re-normalize
+    #[serde(default)]
+    pub dimensions: Option<usize>,
+}
+
+/// Embeddings for a batch of texts, with the number of tokens each text was
+/// encoded to.
+#[derive(Debug)]
+pub struct Embedded {
+    pub embeddings: Vec<Vec<f32>>,
+    pub tokens: Vec<usize>,
+}
+
+const PROMPTS_FILE: &str = "config_sentence_transformers.json";
 
 pub struct Embedder {
+    name: String,
     model_path: PathBuf,
     tokenizer: Arc<tokenizers::Tokenizer>,
     device: Device,
     embedding_dim: usize,
+    memory_bytes: u64,
+    defaults: ModelOptions,
+    dtype: DType,
 }
 
 impl Embedder {
     pub fn load(model_info: &ModelInfo, device: Device) -> Result<Self> {
         tracing::info!("Loading model from: {:?}", model_info.model_path);
 
-        Self::ensure_safetensors_converted(&model_info.model_path)?;
+        convert::ensure_safetensors(&model_info.model_path, false)?;
 
         let config_path = model_info.model_path.join("config.json");
         let config_content = std::fs::read_to_string(&config_path)
@@ -35,59 +80,164 @@ impl Embedder {
                 Error::ModelLoadFailed("Could not determine embedding dimension".to_string())
             })? as usize;
 
-        let model_file = model_info.model_path.join("model.safetensors");
-        let model_file = if model_file.exists() {
-            model_file
-        } else {
-            model_info.model_path.join("pytorch_model.bin")
-        };
+        let model_file = model_info.model_path.join(convert::SAFETENSORS_FILE);
+        let memory_bytes = std::fs::metadata(&model_file).map(|m| m.len()).unwrap_or(0);
+
+        // Truncation happens per request in `encode`, and inputs are embedded
+        // one at a time, so settings shipped in tokenizer.json don't apply
+        let mut tokenizer = tokenizer::load(&model_info.model_path)?;
+        tokenizer.with_padding(None);
+        tokenizer
+            .with_truncation(None)
+            .map_err(|e| Error::ModelLoadFailed(format!("Failed to configure tokenizer: {}", e)))?;
 
-        let tokenizer_path = model_info.model_path.join("tokenizer.json");
-        let tokenizer = tokenizers::Tokenizer::from_file(&tokenizer_path)
-            .map_err(|e| Error::ModelLoadFailed(format!("Failed to load tokenizer: {}", e)))?;
+        // Prefixes configured with `embeddy configure` win over the prompts
+        // the model ships with
+        let mut defaults = model_info.options.clone();
+        let (query_prompt, document_prompt) = read_prompts(&model_info.model_path);
+        defaults.query_prefix = defaults.query_prefix.or(query_prompt);
+        defaults.document_prefix = defaults.document_prefix.or(document_prompt);
 
         tracing::info!("Model loaded successfully");
         tracing::info!("  Embedding dimension: {}", embedding_dim);
 
         Ok(Self {
+            name: model_info
+                .alias
+                .clone()
+                .unwrap_or_else(|| model_info.name.clone()),
             model_path: model_file,
             tokenizer: Arc::new(tokenizer),
             device,
             embedding_dim,
+            memory_bytes,
+            defaults,
+            dtype: model_info.options.dtype.unwrap_or_default().to_candle(),
         })
     }
 
-    pub fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+    pub fn embed(&self, texts: &[String], options: &EmbedOptions) -> Result<Embedded> {
         if texts.is_empty() {
             return Err(Error::InvalidInput("Empty input texts".to_string()));
         }
 
+        self.check_options(options)?;
+
+        let pooling = options
+            .pooling
+            .or(self.defaults.pooling)
+            .unwrap_or_default();
+        let normalize = options
+            .normalize
+            .or(self.defaults.normalize)
+            .unwrap_or(false);
+
         tracing::debug!("Encoding {} texts", texts.len());
+        metrics::BATCH_SIZE
+            .with_label_values(&[&self.name])
+            .observe(texts.len() as f64);
 
         let mut all_embeddings = Vec::with_capacity(texts.len());
+        let mut tokens = Vec::with_capacity(texts.len());
 
         for text in texts {
-            let encoding = self
-                .tokenizer
-                .encode(text.as_str(), true)
-                .map_err(|e| Error::Embedding(format!("Tokenization failed: {}", e)))?;
-
-            let token_ids = encoding.get_ids();
+            let token_ids = self.encode(text, options)?;
+            tokens.push(token_ids.len());
+            metrics::TOKENS_PROCESSED
+                .with_label_values(&[&self.name])
+                .inc_by(token_ids.len() as u64);
 
-            let embeddings = self.embed_tokens(token_ids)?;
+            let embeddings = self.embed_tokens(&token_ids)?;
 
-            let pooled = embeddings
-                .mean(0)
+            let pooled = pool(&embeddings, pooling)
+                .and_then(|pooled| pooled.to_dtype(DType::F32))
                 .map_err(|e| Error::Embedding(format!("Pooling failed: {}", e)))?;
 
-            let embedding_vec = pooled
+            let mut embedding_vec = pooled
                 .to_vec1::<f32>()
                 .map_err(|e| Error::Embedding(format!("Failed to convert to vec: {}", e)))?;
 
+            if let Some(dimensions) = options.dimensions {
+                embedding_vec.truncate(dimensions);
+                l2_normalize(&mut embedding_vec);
+            } else if normalize {
+                l2_normalize(&mut embedding_vec);
+            }
+
             all_embeddings.push(embedding_vec);
         }
 
-        Ok(all_embeddings)
+        Ok(Embedded {
+            embeddings: all_embeddings,
+            tokens,
+        })
+    }
+
+    /// Validate request options against the model, so a bad request fails
+    /// before it is queued.
+    pub fn check_options(&self, options: &EmbedOptions) -> Result<()> {
+        let Some(dimensions) = options.dimensions else {
+            return Ok(());
+        };
+
+        if dimensions == 0 || dimensions > self.embedding_dim {
+            return Err(Error::InvalidInput(format!(
+                "Dimensions must be between 1 and {} for model '{}'",
+                self.embedding_dim, self.name
+            )));
+        }
+        if let Some(supported) = &self.defaults.matryoshka_dims {
+            if dimensions != self.embedding_dim && !supported.contains(&dimensions) {
+                let supported: Vec<String> = supported.iter().map(|d| d.to_string()).collect();
+                return Err(Error::InvalidInput(format!(
+                    "Model '{}' supports dimensions {} (or {})",
+                    self.name,
+                    supported.join(", "),
+                    self.embedding_dim
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn prefix(&self, options: &EmbedOptions) -> Option<&str> {
+        match options.input_type? {
+            InputType::Query => self.defaults.query_prefix.as_deref(),
+            InputType::Document => self.defaults.document_prefix.as_deref(),
+        }
+    }
+
+    /// Token IDs for `text` with the prefix for its input type applied,
+    /// truncated to the maximum sequence length. The text is truncated before
+    /// special tokens are added, so `[CLS]`/`[SEP]` and `<s>`/`</s>` survive.
+    fn encode(&self, text: &str, options: &EmbedOptions) -> Result<Vec<u32>> {
+        let text = match self.prefix(options) {
+            Some(prefix) => format!("{}{}", prefix, text),
+            None => text.to_string(),
+        };
+
+        let tokenization_failed = |e| Error::Embedding(format!("Tokenization failed: {}", e));
+        let mut encoding = self
+            .tokenizer
+            .encode(text, false)
+            .map_err(tokenization_failed)?;
+        if let Some(max) = options.max_seq_length.or(self.defaults.max_seq_length) {
+            let special = self
+                .tokenizer
+                .get_post_processor()
+                .map_or(0, |processor| processor.added_tokens(false));
+            encoding.truncate(
+                max.saturating_sub(special).max(1),
+                0,
+                TruncationDirection::Right,
+            );
+        }
+        let encoding = self
+            .tokenizer
+            .post_process(encoding, None, true)
+            .map_err(tokenization_failed)?;
+
+        Ok(encoding.get_ids().to_vec())
     }
 
     fn embed_tokens(&self, token_ids: &[u32]) -> Result<Tensor> {
@@ -117,6 +267,7 @@ impl Embedder {
 
         let embeddings_weight = safetensors
             .load(&embedding_weight_name, &self.device)
+            .and_then(|weight| weight.to_dtype(self.dtype))
             .map_err(|e| Error::Embedding(format!("Failed to load embedding tensor: {}", e)))?;
 
         let token_ids_tensor = Tensor::new(token_ids, &self.device)
@@ -129,43 +280,79 @@ impl Embedder {
         Ok(token_embeddings)
     }
 
+    /// Estimate how many tokens `texts` will encode to, without tokenizing
+    /// them, so batches can be sized cheaply on the async runtime: about four
+    /// bytes per token plus two special tokens, capped at the maximum
+    /// sequence length. The exact count comes back from [`Embedder::embed`].
+    pub fn estimate_tokens(&self, texts: &[String], options: &EmbedOptions) -> usize {
+        let prefix = self.prefix(options).map_or(0, str::len);
+        let max = options.max_seq_length.or(self.defaults.max_seq_length);
+        texts
+            .iter()
+            .map(|text| {
+                let estimate = (prefix + text.len()).div_ceil(4) + 2;
+                max.map_or(estimate, |max| estimate.min(max.max(1)))
+            })
+            .sum()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
     pub fn embedding_dim(&self) -> usize {
         self.embedding_dim
     }
 
-    fn ensure_safetensors_converted(model_dir: &Path) -> Result<()> {
-        let pytorch_file = model_dir.join("pytorch_model.bin");
-        let safetensors_file = model_dir.join("model.safetensors");
+    /// Calibrated int8 quantization range configured for the model.
+    pub fn int8_range(&self) -> Option<[f32; 2]> {
+        self.defaults.int8_range
+    }
 
-        if safetensors_file.exists() {
-            return Ok(());
-        }
+    /// Estimated memory footprint, based on the size of the weights file.
+    pub fn memory_bytes(&self) -> u64 {
+        self.memory_bytes
+    }
+}
 
-        if !pytorch_file.exists() {
-            return Ok(());
+/// Query and document prompts from `config_sentence_transformers.json`, if
+/// the model has one.
+fn read_prompts(model_path: &Path) -> (Option<String>, Option<String>) {
+    let Ok(content) = std::fs::read_to_string(model_path.join(PROMPTS_FILE)) else {
+        return (None, None);
+    };
+    let config: Value = match serde_json::from_str(&content) {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::warn!("Ignoring invalid {}: {}", PROMPTS_FILE, e);
+            return (None, None);
         }
+    };
 
-        tracing::info!("Converting pytorch_model.bin to model.safetensors...");
-
-        let tensors_vec = pickle::read_all(&pytorch_file)
-            .map_err(|e| Error::ModelLoadFailed(format!("Failed to read PyTorch file: {}", e)))?;
-
-        tracing::info!("Loading {} tensors from PyTorch model", tensors_vec.len());
-
-        let tensors: std::collections::HashMap<_, _> = tensors_vec.into_iter().collect();
-
-        candle_core::safetensors::save(&tensors, &safetensors_file)
-            .map_err(|e| Error::ModelLoadFailed(format!("Failed to save SafeTensors: {}", e)))?;
-
-        tracing::info!("✓ Converted to SafeTensors format");
+    let prompt = |names: &[&str]| {
+        names
+            .iter()
+            .find_map(|name| config["prompts"][name].as_str())
+            .filter(|prompt| !prompt.is_empty())
+            .map(str::to_string)
+    };
+    (prompt(&["query"]), prompt(&["document", "passage", "text"]))
+}
 
-        // Remove the old PyTorch file to save space
-        if let Err(e) = std::fs::remove_file(&pytorch_file) {
-            tracing::warn!("Could not remove pytorch_model.bin: {}", e);
-        } else {
-            tracing::info!("Removed pytorch_model.bin to save space");
-        }
+/// Reduce `(tokens, hidden)` token embeddings to a single `(hidden)` vector.
+fn pool(embeddings: &Tensor, pooling: Pooling) -> candle_core::Result<Tensor> {
+    match pooling {
+        Pooling::Mean => embeddings.mean(0),
+        Pooling::Max => embeddings.max(0),
+        Pooling::Cls => embeddings.get(0),
+        Pooling::Last => embeddings.get(embeddings.dim(0)?.saturating_sub(1)),
+    }
+}
 
-        Ok(())
+/// Scale `vector` to unit length, leaving all-zero vectors unchanged.
+pub fn l2_normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|x| *x /= norm);
     }
 }
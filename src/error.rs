@@ -5,6 +5,11 @@ pub enum Error {
     ModelNotFound(String),
     ModelLoadFailed(String),
     InvalidInput(String),
+    PayloadTooLarge(String),
+    Overloaded(String),
+    Unauthorized(String),
+    Forbidden(String),
+    RateLimited(String),
     DownloadFailed(String),
     Config(String),
     Embedding(String),
@@ -18,6 +23,11 @@ impl fmt::Display for Error {
             Error::ModelNotFound(name) => write!(f, "Model not found: {}", name),
             Error::ModelLoadFailed(msg) => write!(f, "Failed to load model: {}", msg),
             Error::InvalidInput(msg) => write!(f, "Invalid input: {}", msg),
+            Error::PayloadTooLarge(msg) => write!(f, "Payload too large: {}", msg),
+            Error::Overloaded(msg) => write!(f, "Server overloaded: {}", msg),
+            Error::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
+            Error::Forbidden(msg) => write!(f, "Forbidden: {}", msg),
+            Error::RateLimited(msg) => write!(f, "Rate limited: {}", msg),
             Error::DownloadFailed(msg) => write!(f, "Download failed: {}", msg),
             Error::Config(msg) => write!(f, "Configuration error: {}", msg),
             Error::Embedding(msg) => write!(f, "Embedding error: {}", msg),
@@ -29,6 +39,26 @@ impl fmt::Display for Error {
 
 impl std::error::Error for Error {}
 
+impl Clone for Error {
+    fn clone(&self) -> Self {
+        match self {
+            Error::ModelNotFound(name) => Error::ModelNotFound(name.clone()),
+            Error::ModelLoadFailed(msg) => Error::ModelLoadFailed(msg.clone()),
+            Error::InvalidInput(msg) => Error::InvalidInput(msg.clone()),
+            Error::PayloadTooLarge(msg) => Error::PayloadTooLarge(msg.clone()),
+            Error::Overloaded(msg) => Error::Overloaded(msg.clone()),
+            Error::Unauthorized(msg) => Error::Unauthorized(msg.clone()),
+            Error::Forbidden(msg) => Error::Forbidden(msg.clone()),
+            Error::RateLimited(msg) => Error::RateLimited(msg.clone()),
+            Error::DownloadFailed(msg) => Error::DownloadFailed(msg.clone()),
+            Error::Config(msg) => Error::Config(msg.clone()),
+            Error::Embedding(msg) => Error::Embedding(msg.clone()),
+            Error::Io(e) => Error::Io(std::io::Error::new(e.kind(), e.to_string())),
+            Error::Serialization(msg) => Error::Serialization(msg.clone()),
+        }
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Self {
         Error::Io(err)
@@ -2,12 +2,13 @@ mod cli;
 mod config;
 mod embedder;
 mod error;
+mod metrics;
 mod model;
 mod server;
 
 use candle_core::Device;
 use clap::Parser;
-use cli::{Cli, Commands};
+use cli::{Cli, Commands, ConfigCommands};
 use config::Config;
 use error::Result;
 use model::ModelDownloader;
@@ -35,6 +36,15 @@ fn parse_device(device_str: &str) -> Result<Device> {
     }
 }
 
+/// Settings from the config file: `--config` if given (and then required to
+/// exist), otherwise `config.toml` in the data directory if present.
+fn load_settings(config: &Config, path: Option<&std::path::Path>) -> Result<config::Settings> {
+    match path {
+        Some(path) => config::Settings::load(path, true),
+        None => config::Settings::load(&config.config_path, false),
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     tracing_subscriber::fmt()
@@ -60,18 +70,58 @@ async fn main() -> Result<()> {
             }
         }
 
-        Commands::Serve { device, port, host } => {
-            let device = parse_device(&device)?;
+        Commands::Serve(args) => {
+            let settings =
+                load_settings(&config, cli.config.as_deref())?.merge(args.into_settings());
+
+            let host = settings
+                .server
+                .host
+                .clone()
+                .unwrap_or_else(|| config::DEFAULT_HOST.to_string());
+            let port = settings.server.port.unwrap_or(config::DEFAULT_PORT);
+            let device = parse_device(
+                settings
+                    .server
+                    .device
+                    .as_deref()
+                    .unwrap_or(config::DEFAULT_DEVICE),
+            )?;
             let device_name = format!("{:?}", device);
 
-            let state = server::AppState::new(config, device);
+            let options = server::ServerOptions::from_settings(&settings)?;
+            let workers = options.workers;
+
+            let state = server::AppState::new(config, device, options);
 
             println!("🚀 Embeddy server starting...");
             println!("   Device: {}", device_name);
-            println!("   Listening on: http://{}:{}", host, port);
-            println!("   Health: http://{}:{}/api/health", host, port);
-            println!("   Embed: http://{}:{}/api/embed", host, port);
-            println!("\n   Models will be loaded on-demand when requested via API");
+            println!("   Workers: {}", workers);
+            if !state.options().api_keys.is_empty() {
+                println!(
+                    "   Auth: {} API key(s) required",
+                    state.options().api_keys.len()
+                );
+            }
+            let scheme = match &state.options().tls {
+                Some(tls) if tls.client_ca.is_some() => {
+                    println!("   TLS: client certificates required");
+                    "https"
+                }
+                Some(_) => "https",
+                None => "http",
+            };
+            println!("   Listening on: {}://{}:{}", scheme, host, port);
+            println!("   Health: {}://{}:{}/api/health", scheme, host, port);
+            println!("   Embed: {}://{}:{}/api/embed", scheme, host, port);
+            if let Some(grpc_port) = state.options().grpc_port {
+                println!("   gRPC: {}:{}", host, grpc_port);
+            }
+            if state.options().preload.is_empty() {
+                println!("\n   Models will be loaded on-demand when requested via API");
+            } else {
+                println!("\n   Preloading: {}", state.options().preload.join(", "));
+            }
 
             server::serve(&host, port, state).await?;
         }
@@ -80,6 +130,13 @@ async fn main() -> Result<()> {
             model,
             text,
             device,
+            input_type,
+            pooling,
+            normalize,
+            max_seq_length,
+            dimensions,
+            encoding_format,
+            int8_range,
         } => {
             if text.is_empty() {
                 return Err(error::Error::InvalidInput(
@@ -96,17 +153,52 @@ async fn main() -> Result<()> {
             let embedder = embedder::Embedder::load(model_info, device)?;
 
             tracing::info!("Generating embeddings for {} texts", text.len());
-            let embeddings = embedder.embed(&text)?;
+            let options = embedder::EmbedOptions {
+                input_type,
+                pooling,
+                normalize,
+                max_seq_length,
+                dimensions,
+            };
+            let encoding = embedder::encoding::Encoding {
+                encoding_format,
+                int8_range,
+            };
+            encoding.check()?;
+            let embedded = embedder.embed(&text, &options)?;
+            let encoded = encoding.apply(embedded.embeddings, embedder.int8_range())?;
 
-            let output = serde_json::json!({
+            let mut output = serde_json::json!({
                 "model": model,
-                "dimension": embedder.embedding_dim(),
-                "embeddings": embeddings,
+                "dimension": options.dimensions.unwrap_or(embedder.embedding_dim()),
+                "embeddings": encoded.embeddings,
             });
+            if let Some(range) = encoded.int8_range {
+                output["int8_range"] = serde_json::json!(range);
+            }
 
             println!("{}", serde_json::to_string_pretty(&output).unwrap());
         }
 
+        Commands::Configure(args) => {
+            let mut registry = model::ModelRegistry::load(&config)?;
+            let model_info = registry.get_model_mut(&args.model)?;
+            let name = args.model.clone();
+            args.apply(&mut model_info.options);
+
+            let options = toml::to_string(&model_info.options)?;
+            registry.save(&config)?;
+
+            println!("✓ Configured model: {}", name);
+            if options.is_empty() {
+                println!("  Using default options");
+            } else {
+                for line in options.lines() {
+                    println!("  {}", line);
+                }
+            }
+        }
+
         Commands::List => {
             let registry = model::ModelRegistry::load(&config)?;
             let models = registry.list_models();
@@ -124,10 +216,69 @@ async fn main() -> Result<()> {
                     if let Some(dim) = model.embedding_dim {
                         println!("    Dimension: {}", dim);
                     }
+                    if model.options != model::ModelOptions::default() {
+                        let options = toml::to_string(&model.options)?;
+                        println!("    Options: {}", options.trim().replace('\n', ", "));
+                    }
                     println!();
                 }
             }
         }
+
+        Commands::Config { command } => match command {
+            ConfigCommands::Show(args) => {
+                let settings = config::Settings::defaults()
+                    .merge(load_settings(&config, cli.config.as_deref())?)
+                    .merge(args.into_settings());
+
+                println!("# Data directory: {:?}", config.data_dir);
+                println!(
+                    "# Config file: {:?}",
+                    cli.config.as_deref().unwrap_or(&config.config_path)
+                );
+                println!("{}", toml::to_string_pretty(&settings.redacted())?);
+            }
+        },
+
+        Commands::Convert { model, prune } => {
+            let registry = model::ModelRegistry::load(&config)?;
+            let model_info = registry.get_model(&model)?;
+
+            let report = model::convert::ensure_safetensors(&model_info.model_path, prune)?;
+            if !model_info
+                .model_path
+                .join(model::convert::SAFETENSORS_FILE)
+                .exists()
+            {
+                return Err(error::Error::ModelLoadFailed(format!(
+                    "No weights found for model '{}': expected {} or {} in {}",
+                    model,
+                    model::convert::SAFETENSORS_FILE,
+                    model::convert::PYTORCH_FILE,
+                    model_info.model_path.display()
+                )));
+            }
+
+            if report.converted {
+                println!("✓ Converted model: {}", model);
+                println!("  Tensors: {}", report.tensors);
+                if report.shared > 0 {
+                    println!("  Shared tensors: {}", report.shared);
+                }
+            } else {
+                println!("Model '{}' is already in SafeTensors format.", model);
+            }
+            if report.pruned && report.freed_bytes > 0 {
+                println!(
+                    "  Removed pytorch_model.bin ({} bytes freed)",
+                    report.freed_bytes
+                );
+            } else if report.pruned {
+                println!(
+                    "  Removed the pytorch_model.bin link; the file it points to is still in use"
+                );
+            }
+        }
     }
 
     Ok(())
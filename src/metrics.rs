@@ -0,0 +1,125 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+
+pub static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::new);
+
+pub static HTTP_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new("embeddy_http_requests_total", "HTTP requests handled"),
+        &["route", "model", "status", "key"],
+    ))
+});
+
+pub static HTTP_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "embeddy_http_request_duration_seconds",
+            "HTTP request latency in seconds",
+        ),
+        &["route", "model", "status"],
+    ))
+});
+
+pub static TOKENS_PROCESSED: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register(IntCounterVec::new(
+        Opts::new(
+            "embeddy_tokens_processed_total",
+            "Tokens run through models",
+        ),
+        &["model"],
+    ))
+});
+
+pub static BATCH_SIZE: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new("embeddy_batch_size", "Number of texts per inference batch")
+            .buckets(vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0]),
+        &["model"],
+    ))
+});
+
+pub static QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new(
+        "embeddy_queue_depth",
+        "Tasks waiting for an inference worker",
+    ))
+});
+
+pub static BATCH_QUEUE_DEPTH: LazyLock<IntGaugeVec> = LazyLock::new(|| {
+    register(IntGaugeVec::new(
+        Opts::new(
+            "embeddy_batch_queue_depth",
+            "Requests waiting in a model's batching queue",
+        ),
+        &["model"],
+    ))
+});
+
+pub static MODEL_LOAD_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register(HistogramVec::new(
+        HistogramOpts::new(
+            "embeddy_model_load_duration_seconds",
+            "Time taken to load a model in seconds",
+        )
+        .buckets(vec![0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0]),
+        &["model"],
+    ))
+});
+
+pub static LOADED_MODELS: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new(
+        "embeddy_loaded_models",
+        "Number of models currently loaded",
+    ))
+});
+
+pub static LOADED_MEMORY_BYTES: LazyLock<IntGauge> = LazyLock::new(|| {
+    register(IntGauge::new(
+        "embeddy_loaded_memory_bytes",
+        "Estimated memory used by loaded models",
+    ))
+});
+
+fn register<C>(collector: prometheus::Result<C>) -> C
+where
+    C: prometheus::core::Collector + Clone + 'static,
+{
+    let collector = collector.expect("invalid metric definition");
+    REGISTRY
+        .register(Box::new(collector.clone()))
+        .expect("metric registered twice");
+    collector
+}
+
+/// Increments a gauge for as long as the guard is alive.
+pub struct GaugeGuard(&'static IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &'static IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Render every registered metric in the Prometheus text exposition format.
+pub fn render() -> String {
+    // Make sure unlabelled metrics show up before they are first touched.
+    LazyLock::force(&QUEUE_DEPTH);
+    LazyLock::force(&LOADED_MODELS);
+    LazyLock::force(&LOADED_MEMORY_BYTES);
+
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&REGISTRY.gather(), &mut buffer) {
+        tracing::error!("Failed to encode metrics: {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
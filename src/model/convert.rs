@@ -0,0 +1,285 @@
+use crate::error::{Error, Result};
+use candle_core::pickle::PthTensors;
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::Tensor;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+pub const PYTORCH_FILE: &str = "pytorch_model.bin";
+pub const SAFETENSORS_FILE: &str = "model.safetensors";
+
+#[derive(Debug, Default)]
+pub struct ConversionReport {
+    /// Whether a new `model.safetensors` was written.
+    pub converted: bool,
+    pub tensors: usize,
+    /// Tensors that shared storage with another tensor (e.g. tied weights).
+    pub shared: usize,
+    pub pruned: bool,
+    /// Disk space the prune freed; zero when the file was a link to a blob
+    /// that is kept.
+    pub freed_bytes: u64,
+}
+
+/// Make sure `model_dir` contains a `model.safetensors` file, converting from
+/// `pytorch_model.bin` when needed.
+///
+/// The new file is written to a temporary path, verified against the source
+/// tensors and then renamed into place, so an interrupted conversion never
+/// leaves a truncated `model.safetensors` behind. The PyTorch file is only
+/// removed when `prune` is set and the safetensors file verified cleanly,
+/// and is otherwise not read at all once `model.safetensors` exists.
+pub fn ensure_safetensors(model_dir: &Path, prune: bool) -> Result<ConversionReport> {
+    let pytorch_file = model_dir.join(PYTORCH_FILE);
+    let safetensors_file = model_dir.join(SAFETENSORS_FILE);
+
+    let mut report = ConversionReport::default();
+
+    // A leftover (possibly corrupt) PyTorch file must not stop a converted
+    // model from loading
+    if !pytorch_file.exists() || (safetensors_file.exists() && !prune) {
+        return Ok(report);
+    }
+
+    let pth = PthTensors::new(&pytorch_file, None)
+        .map_err(|e| Error::ModelLoadFailed(format!("Failed to read PyTorch file: {}", e)))?;
+
+    if !safetensors_file.exists() {
+        tracing::info!("Converting {} to {}...", PYTORCH_FILE, SAFETENSORS_FILE);
+
+        let (tensors, shared) = read_tensors(&pth)?;
+        report.tensors = tensors.len();
+        report.shared = shared;
+
+        tracing::info!(
+            "Loaded {} tensors from PyTorch model ({} shared)",
+            tensors.len(),
+            shared
+        );
+
+        let tmp_file = model_dir.join(format!("{}.tmp", SAFETENSORS_FILE));
+        let written = candle_core::safetensors::save(&tensors, &tmp_file)
+            .map_err(|e| Error::ModelLoadFailed(format!("Failed to save SafeTensors: {}", e)))
+            .and_then(|_| verify(&pth, &tmp_file));
+
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp_file);
+            return Err(e);
+        }
+
+        fs::rename(&tmp_file, &safetensors_file)?;
+        report.converted = true;
+
+        tracing::info!("✓ Converted to SafeTensors format");
+    } else if prune {
+        // Never prune the only usable copy of the weights.
+        verify(&pth, &safetensors_file)?;
+    }
+
+    if prune {
+        report.freed_bytes = remove_weights(&pytorch_file)?;
+        report.pruned = true;
+        if report.freed_bytes > 0 {
+            tracing::info!(
+                "Removed {} ({} bytes freed)",
+                PYTORCH_FILE,
+                report.freed_bytes
+            );
+        } else {
+            tracing::info!(
+                "Removed the {} link; its blob is still used elsewhere",
+                PYTORCH_FILE
+            );
+        }
+    }
+
+    Ok(report)
+}
+
+/// Remove a weights file and return the disk space freed.
+///
+/// In the HF cache, `snapshots/<revision>/<file>` is a symlink to
+/// `blobs/<hash>`. The blob is removed along with the link unless another
+/// snapshot still links to it; links pointing anywhere else only lose the
+/// link.
+fn remove_weights(path: &Path) -> Result<u64> {
+    if !fs::symlink_metadata(path)?.file_type().is_symlink() {
+        let size = fs::metadata(path)?.len();
+        fs::remove_file(path)?;
+        return Ok(size);
+    }
+
+    let target = fs::canonicalize(path)?;
+    fs::remove_file(path)?;
+
+    let Some(snapshots) = path.parent().and_then(Path::parent) else {
+        return Ok(0);
+    };
+    let repo = snapshots.parent();
+    let is_cache_blob = snapshots
+        .file_name()
+        .is_some_and(|name| name == "snapshots")
+        && repo.is_some_and(|repo| {
+            fs::canonicalize(repo.join("blobs")).ok().as_deref() == target.parent()
+        });
+    if !is_cache_blob || links_to(snapshots, &target) {
+        return Ok(0);
+    }
+
+    let size = fs::metadata(&target)?.len();
+    fs::remove_file(&target)?;
+    Ok(size)
+}
+
+/// Whether any file under `dir` resolves to `target`.
+fn links_to(dir: &Path, target: &Path) -> bool {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let path = entry.path();
+        if path.is_dir() {
+            links_to(&path, target)
+        } else {
+            fs::canonicalize(&path).is_ok_and(|resolved| resolved == target)
+        }
+    })
+}
+
+/// Load every tensor from the PyTorch checkpoint.
+///
+/// Tensors backed by the same storage with the same layout (tied weights) are
+/// only read once; safetensors has no notion of shared storage, so each name
+/// gets its own copy in the output file.
+fn read_tensors(pth: &PthTensors) -> Result<(HashMap<String, Tensor>, usize)> {
+    let mut by_storage: HashMap<String, Tensor> = HashMap::new();
+    let mut tensors = HashMap::new();
+    let mut shared = 0;
+
+    for (name, info) in pth.tensor_infos() {
+        // Views of one storage with the same shape can still differ in
+        // strides, e.g. a square weight and its transpose
+        let key = format!(
+            "{}:{:?}:{:?}:{:?}:{:?}",
+            info.path,
+            info.dtype,
+            info.layout.start_offset(),
+            info.layout.dims(),
+            info.layout.stride()
+        );
+
+        let tensor = match by_storage.get(&key) {
+            Some(tensor) => {
+                shared += 1;
+                tensor.clone()
+            }
+            None => {
+                let tensor = pth
+                    .get(name)
+                    .and_then(|t| t.map(|t| t.contiguous()).transpose())
+                    .map_err(|e| {
+                        Error::ModelLoadFailed(format!("Failed to read tensor '{}': {}", name, e))
+                    })?
+                    .ok_or_else(|| Error::ModelLoadFailed(format!("Missing tensor '{}'", name)))?;
+                by_storage.insert(key, tensor.clone());
+                tensor
+            }
+        };
+
+        tensors.insert(name.clone(), tensor);
+    }
+
+    Ok((tensors, shared))
+}
+
+/// Check that `safetensors_file` holds the same tensor names, dtypes and
+/// shapes as the PyTorch checkpoint.
+fn verify(pth: &PthTensors, safetensors_file: &Path) -> Result<()> {
+    let safetensors = unsafe {
+        MmapedSafetensors::new(safetensors_file)
+            .map_err(|e| Error::ModelLoadFailed(format!("Failed to open SafeTensors: {}", e)))?
+    };
+
+    let written: HashMap<String, _> = safetensors.tensors().into_iter().collect();
+    let expected = pth.tensor_infos();
+
+    if written.len() != expected.len() {
+        return Err(Error::ModelLoadFailed(format!(
+            "Conversion verification failed: expected {} tensors, found {}",
+            expected.len(),
+            written.len()
+        )));
+    }
+
+    for (name, info) in expected {
+        let view = written.get(name).ok_or_else(|| {
+            Error::ModelLoadFailed(format!(
+                "Conversion verification failed: missing tensor '{}'",
+                name
+            ))
+        })?;
+
+        if view.shape() != info.layout.dims() {
+            return Err(Error::ModelLoadFailed(format!(
+                "Conversion verification failed: tensor '{}' has shape {:?}, expected {:?}",
+                name,
+                view.shape(),
+                info.layout.dims()
+            )));
+        }
+
+        let dtype = candle_core::DType::try_from(view.dtype()).map_err(|e| {
+            Error::ModelLoadFailed(format!("Conversion verification failed: {}", e))
+        })?;
+        if dtype != info.dtype {
+            return Err(Error::ModelLoadFailed(format!(
+                "Conversion verification failed: tensor '{}' has dtype {:?}, expected {:?}",
+                name, dtype, info.dtype
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+
+    /// An HF cache repo with `pytorch_model.bin` in the given revisions, all
+    /// linking to one blob.
+    fn cache_repo(name: &str, revisions: &[&str]) -> std::path::PathBuf {
+        let repo =
+            std::env::temp_dir().join(format!("embeddy-convert-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&repo);
+        fs::create_dir_all(repo.join("blobs")).unwrap();
+        fs::write(repo.join("blobs").join("abc123"), [0u8; 64]).unwrap();
+        for revision in revisions {
+            let snapshot = repo.join("snapshots").join(revision);
+            fs::create_dir_all(&snapshot).unwrap();
+            symlink("../../blobs/abc123", snapshot.join(PYTORCH_FILE)).unwrap();
+        }
+        repo
+    }
+
+    #[test]
+    fn prunes_unshared_blobs() {
+        let repo = cache_repo("unshared", &["main"]);
+        let link = repo.join("snapshots/main").join(PYTORCH_FILE);
+
+        assert_eq!(remove_weights(&link).unwrap(), 64);
+        assert!(!repo.join("blobs/abc123").exists());
+    }
+
+    #[test]
+    fn keeps_blobs_other_revisions_link_to() {
+        let repo = cache_repo("shared", &["main", "v1"]);
+        let link = repo.join("snapshots/main").join(PYTORCH_FILE);
+
+        assert_eq!(remove_weights(&link).unwrap(), 0);
+        assert!(fs::symlink_metadata(&link).is_err());
+        assert!(repo.join("blobs/abc123").exists());
+    }
+}
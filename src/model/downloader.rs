@@ -1,9 +1,7 @@
 use crate::config::Config;
 use crate::error::{Error, Result};
-use crate::model::{ModelInfo, ModelRegistry};
-use candle_core::pickle;
+use crate::model::{convert, tokenizer, ModelInfo, ModelRegistry};
 use hf_hub::api::sync::Api;
-use std::path::Path;
 
 pub struct ModelDownloader {
     config: Config,
@@ -30,9 +28,21 @@ impl ModelDownloader {
             .or_else(|_| repo.get("pytorch_model.bin"))
             .map_err(|e| Error::DownloadFailed(format!("Could not find model file: {}", e)))?;
 
-        let _tokenizer_file = repo
-            .get("tokenizer.json")
-            .map_err(|e| Error::DownloadFailed(format!("Could not find tokenizer: {}", e)))?;
+        let _tokenizer_file = tokenizer::TOKENIZER_FILES
+            .iter()
+            .find_map(|file| repo.get(file).ok())
+            .ok_or_else(|| {
+                Error::DownloadFailed(format!(
+                    "Could not find tokenizer (tried {})",
+                    tokenizer::TOKENIZER_FILES.join(", ")
+                ))
+            })?;
+
+        // Only needed when rebuilding a tokenizer without tokenizer.json
+        let _ = repo.get(tokenizer::TOKENIZER_CONFIG_FILE);
+
+        // Query and document prompts, for models that ship them
+        let _ = repo.get("config_sentence_transformers.json");
 
         let _config_file = repo
             .get("config.json")
@@ -43,7 +53,7 @@ impl ModelDownloader {
             .ok_or_else(|| Error::DownloadFailed("Invalid model path".to_string()))?;
 
         // Auto-convert PyTorch to SafeTensors if needed
-        Self::ensure_safetensors(model_dir)?;
+        convert::ensure_safetensors(model_dir, false)?;
 
         let name = alias.clone().unwrap_or_else(|| {
             hf_repo_id
@@ -60,6 +70,7 @@ impl ModelDownloader {
             model_path: model_dir.to_path_buf(),
             embedding_dim: None,
             downloaded_at: chrono::Utc::now().to_rfc3339(),
+            options: Default::default(),
         };
 
         self.registry.add_model(model_info.clone());
@@ -69,45 +80,4 @@ impl ModelDownloader {
 
         Ok(model_info)
     }
-
-    fn ensure_safetensors(model_dir: &Path) -> Result<()> {
-        let pytorch_file = model_dir.join("pytorch_model.bin");
-        let safetensors_file = model_dir.join("model.safetensors");
-
-        // If safetensors exists, we're good
-        if safetensors_file.exists() {
-            return Ok(());
-        }
-
-        // If pytorch file doesn't exist, nothing to convert
-        if !pytorch_file.exists() {
-            return Ok(());
-        }
-
-        tracing::info!("Converting pytorch_model.bin to model.safetensors...");
-
-        // Read PyTorch file and load all tensors
-        let tensors_vec = pickle::read_all(&pytorch_file)
-            .map_err(|e| Error::ModelLoadFailed(format!("Failed to read PyTorch file: {}", e)))?;
-
-        tracing::info!("Loading {} tensors from PyTorch model", tensors_vec.len());
-
-        // Convert to HashMap
-        let tensors: std::collections::HashMap<_, _> = tensors_vec.into_iter().collect();
-
-        // Save as safetensors
-        candle_core::safetensors::save(&tensors, &safetensors_file)
-            .map_err(|e| Error::ModelLoadFailed(format!("Failed to save SafeTensors: {}", e)))?;
-
-        tracing::info!("✓ Converted to SafeTensors format");
-
-        // Remove the old PyTorch file to save space
-        if let Err(e) = std::fs::remove_file(&pytorch_file) {
-            tracing::warn!("Could not remove pytorch_model.bin: {}", e);
-        } else {
-            tracing::info!("Removed pytorch_model.bin to save space");
-        }
-
-        Ok(())
-    }
 }
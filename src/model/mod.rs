@@ -1,5 +1,7 @@
+pub mod convert;
 pub mod downloader;
 pub mod registry;
+pub mod tokenizer;
 
 pub use downloader::ModelDownloader;
-pub use registry::{ModelInfo, ModelRegistry};
+pub use registry::{ModelDType, ModelInfo, ModelOptions, ModelRegistry, Pooling};
@@ -13,6 +13,93 @@ pub struct ModelInfo {
     pub model_path: PathBuf,
     pub embedding_dim: Option<usize>,
     pub downloaded_at: String,
+    #[serde(flatten)]
+    pub options: ModelOptions,
+}
+
+/// How token embeddings are reduced to a single vector.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Pooling {
+    #[default]
+    Mean,
+    /// First token ([CLS])
+    Cls,
+    Max,
+    /// Last token, for decoder models
+    Last,
+}
+
+/// Precision the model weights are run in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelDType {
+    #[default]
+    F32,
+    F16,
+    Bf16,
+}
+
+impl ModelDType {
+    pub fn to_candle(self) -> candle_core::DType {
+        match self {
+            ModelDType::F32 => candle_core::DType::F32,
+            ModelDType::F16 => candle_core::DType::F16,
+            ModelDType::Bf16 => candle_core::DType::BF16,
+        }
+    }
+}
+
+/// Per-model defaults, set with `embeddy configure`. Requests may override
+/// pooling, normalization and sequence length.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ModelOptions {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pooling: Option<Pooling>,
+    /// L2-normalize embeddings
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub normalize: Option<bool>,
+    /// Inputs are truncated to this many tokens
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_seq_length: Option<usize>,
+    /// Prepended to query inputs (e.g. "query: " for e5). Falls back to the
+    /// `query` prompt in `config_sentence_transformers.json`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_prefix: Option<String>,
+    /// Prepended to document inputs (e.g. "passage: " for e5). Falls back to
+    /// the `document` or `passage` prompt in `config_sentence_transformers.json`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub document_prefix: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dtype: Option<ModelDType>,
+    /// Sizes a Matryoshka-trained model's embeddings may be truncated to.
+    /// Any size up to the full dimension is accepted when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub matryoshka_dims: Option<Vec<usize>>,
+    /// `[min, max]` range calibrated on the model's embeddings, used for
+    /// `int8` encoding when a request doesn't give one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub int8_range: Option<[f32; 2]>,
+}
+
+impl ModelOptions {
+    /// These options, with any left unset taken from `defaults`.
+    pub fn with_defaults(self, defaults: &ModelOptions) -> Self {
+        Self {
+            pooling: self.pooling.or(defaults.pooling),
+            normalize: self.normalize.or(defaults.normalize),
+            max_seq_length: self.max_seq_length.or(defaults.max_seq_length),
+            query_prefix: self.query_prefix.or_else(|| defaults.query_prefix.clone()),
+            document_prefix: self
+                .document_prefix
+                .or_else(|| defaults.document_prefix.clone()),
+            dtype: self.dtype.or(defaults.dtype),
+            matryoshka_dims: self
+                .matryoshka_dims
+                .or_else(|| defaults.matryoshka_dims.clone()),
+            int8_range: self.int8_range.or(defaults.int8_range),
+        }
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
@@ -48,6 +135,12 @@ impl ModelRegistry {
             .ok_or_else(|| Error::ModelNotFound(name.to_string()))
     }
 
+    pub fn get_model_mut(&mut self, name: &str) -> Result<&mut ModelInfo> {
+        self.models
+            .get_mut(name)
+            .ok_or_else(|| Error::ModelNotFound(name.to_string()))
+    }
+
     pub fn list_models(&self) -> Vec<&ModelInfo> {
         self.models.values().collect()
     }
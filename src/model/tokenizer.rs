@@ -0,0 +1,738 @@
+use crate::error::{Error, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use tokenizers::decoders::metaspace::{Metaspace, PrependScheme};
+use tokenizers::decoders::wordpiece::WordPiece as WordPieceDecoder;
+use tokenizers::models::bpe::BPE;
+use tokenizers::models::unigram::Unigram;
+use tokenizers::models::wordpiece::WordPiece;
+use tokenizers::normalizers::replace::ReplacePattern;
+use tokenizers::normalizers::{BertNormalizer, Lowercase, Precompiled, Replace, Sequence};
+use tokenizers::pre_tokenizers::bert::BertPreTokenizer;
+use tokenizers::processors::bert::BertProcessing;
+use tokenizers::processors::template::TemplateProcessing;
+use tokenizers::{AddedToken, NormalizerWrapper, Tokenizer};
+
+pub const TOKENIZER_FILE: &str = "tokenizer.json";
+pub const TOKENIZER_CONFIG_FILE: &str = "tokenizer_config.json";
+pub const VOCAB_FILE: &str = "vocab.txt";
+pub const SENTENCEPIECE_FILES: &[&str] = &[
+    FAIRSEQ_SENTENCEPIECE_FILE,
+    "spiece.model",
+    "tokenizer.model",
+];
+/// Sentencepiece file name used by fairseq-trained checkpoints
+const FAIRSEQ_SENTENCEPIECE_FILE: &str = "sentencepiece.bpe.model";
+
+/// Tokenizer files in order of preference; a model needs at least one of them.
+pub const TOKENIZER_FILES: &[&str] = &[
+    TOKENIZER_FILE,
+    VOCAB_FILE,
+    "sentencepiece.bpe.model",
+    "spiece.model",
+    "tokenizer.model",
+];
+
+/// Load the tokenizer for a model directory.
+///
+/// `tokenizer.json` is used when present. Otherwise the tokenizer is rebuilt
+/// from a WordPiece `vocab.txt` or a sentencepiece model, using
+/// `tokenizer_config.json` for lowercasing and special tokens.
+pub fn load(model_dir: &Path) -> Result<Tokenizer> {
+    let tokenizer_file = model_dir.join(TOKENIZER_FILE);
+    if tokenizer_file.exists() {
+        return Tokenizer::from_file(&tokenizer_file)
+            .map_err(|e| Error::ModelLoadFailed(format!("Failed to load tokenizer: {}", e)));
+    }
+
+    let config = TokenizerConfig::load(model_dir)?;
+
+    let vocab_file = model_dir.join(VOCAB_FILE);
+    if vocab_file.exists() {
+        tracing::info!("No {} found, building WordPiece tokenizer", TOKENIZER_FILE);
+        return from_wordpiece(&vocab_file, &config);
+    }
+
+    for name in SENTENCEPIECE_FILES {
+        let spm_file = model_dir.join(name);
+        if spm_file.exists() {
+            tracing::info!(
+                "No {} found, building tokenizer from {}",
+                TOKENIZER_FILE,
+                name
+            );
+            let fairseq = is_fairseq(model_dir, name, &config);
+            return from_sentencepiece(&spm_file, &config, fairseq);
+        }
+    }
+
+    Err(Error::ModelLoadFailed(format!(
+        "No tokenizer found in {:?} (expected one of: {})",
+        model_dir,
+        TOKENIZER_FILES.join(", ")
+    )))
+}
+
+/// XLM-R family checkpoints were trained with fairseq, which shifts the
+/// sentencepiece ids and adds its own control tokens around them. Not every
+/// checkpoint names its tokenizer class, so the model type and fairseq's
+/// `sentencepiece.bpe.model` file name count as well.
+fn is_fairseq(model_dir: &Path, spm_name: &str, config: &TokenizerConfig) -> bool {
+    #[derive(Deserialize)]
+    struct ModelConfig {
+        model_type: Option<String>,
+    }
+
+    let class_is_xlmr = config
+        .tokenizer_class
+        .as_deref()
+        .is_some_and(|class| class.starts_with("XLMRoberta"));
+    let model_is_xlmr = std::fs::read_to_string(model_dir.join("config.json"))
+        .ok()
+        .and_then(|content| serde_json::from_str::<ModelConfig>(&content).ok())
+        .and_then(|model_config| model_config.model_type)
+        .is_some_and(|model_type| model_type.starts_with("xlm-roberta"));
+
+    class_is_xlmr || model_is_xlmr || spm_name == FAIRSEQ_SENTENCEPIECE_FILE
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TokenizerConfig {
+    tokenizer_class: Option<String>,
+    do_lower_case: Option<bool>,
+    unk_token: Option<SpecialToken>,
+    cls_token: Option<SpecialToken>,
+    sep_token: Option<SpecialToken>,
+    pad_token: Option<SpecialToken>,
+    mask_token: Option<SpecialToken>,
+    bos_token: Option<SpecialToken>,
+    eos_token: Option<SpecialToken>,
+}
+
+/// Special tokens are either plain strings or serialized `AddedToken`s.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SpecialToken {
+    Content(String),
+    Added { content: String },
+}
+
+impl SpecialToken {
+    fn content(&self) -> &str {
+        match self {
+            SpecialToken::Content(content) | SpecialToken::Added { content } => content,
+        }
+    }
+}
+
+impl TokenizerConfig {
+    fn load(model_dir: &Path) -> Result<Self> {
+        let path = model_dir.join(TOKENIZER_CONFIG_FILE);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        serde_json::from_str(&content).map_err(|e| {
+            Error::ModelLoadFailed(format!("Failed to parse {}: {}", TOKENIZER_CONFIG_FILE, e))
+        })
+    }
+
+    fn token(token: &Option<SpecialToken>) -> Option<String> {
+        token.as_ref().map(|t| t.content().to_string())
+    }
+
+    fn special_tokens(&self) -> Vec<String> {
+        [
+            &self.unk_token,
+            &self.cls_token,
+            &self.sep_token,
+            &self.pad_token,
+            &self.mask_token,
+            &self.bos_token,
+            &self.eos_token,
+        ]
+        .into_iter()
+        .filter_map(Self::token)
+        .collect()
+    }
+}
+
+fn from_wordpiece(vocab_file: &Path, config: &TokenizerConfig) -> Result<Tokenizer> {
+    let unk = TokenizerConfig::token(&config.unk_token).unwrap_or_else(|| "[UNK]".to_string());
+    let cls = TokenizerConfig::token(&config.cls_token).unwrap_or_else(|| "[CLS]".to_string());
+    let sep = TokenizerConfig::token(&config.sep_token).unwrap_or_else(|| "[SEP]".to_string());
+
+    let vocab_path = vocab_file.to_string_lossy();
+    let model = WordPiece::from_file(&vocab_path)
+        .unk_token(unk)
+        .build()
+        .map_err(|e| Error::ModelLoadFailed(format!("Failed to load {}: {}", VOCAB_FILE, e)))?;
+
+    let mut tokenizer = Tokenizer::new(model);
+    tokenizer
+        .with_normalizer(Some(BertNormalizer::new(
+            true,
+            true,
+            None,
+            config.do_lower_case.unwrap_or(true),
+        )))
+        .with_pre_tokenizer(Some(BertPreTokenizer))
+        .with_decoder(Some(WordPieceDecoder::default()));
+
+    let cls_id = token_id(&tokenizer, &cls)?;
+    let sep_id = token_id(&tokenizer, &sep)?;
+    tokenizer.with_post_processor(Some(BertProcessing::new((sep, sep_id), (cls, cls_id))));
+
+    add_special_tokens(&mut tokenizer, config);
+    Ok(tokenizer)
+}
+
+fn from_sentencepiece(
+    spm_file: &Path,
+    config: &TokenizerConfig,
+    fairseq: bool,
+) -> Result<Tokenizer> {
+    let bytes = std::fs::read(spm_file)?;
+    let spm = SentencePieceModel::parse(&bytes)?;
+    let (vocab, unk_id) = sentencepiece_vocab(&spm, fairseq);
+
+    let mut tokenizer = match spm.model_type {
+        MODEL_TYPE_UNIGRAM => {
+            let model = Unigram::from(vocab, unk_id, spm.byte_fallback).map_err(|e| {
+                Error::ModelLoadFailed(format!("Failed to build Unigram model: {}", e))
+            })?;
+            Tokenizer::new(model)
+        }
+        MODEL_TYPE_BPE => {
+            let merges = sentencepiece_merges(&vocab);
+            let unk_token = unk_id.map(|id| vocab[id].0.clone());
+            let vocab: tokenizers::models::bpe::Vocab = vocab
+                .into_iter()
+                .enumerate()
+                .map(|(id, (piece, _))| (piece, id as u32))
+                .collect();
+            let mut builder = BPE::builder()
+                .vocab_and_merges(vocab, merges)
+                .fuse_unk(true)
+                .byte_fallback(spm.byte_fallback);
+            if let Some(unk_token) = unk_token {
+                builder = builder.unk_token(unk_token);
+            }
+            let model = builder
+                .build()
+                .map_err(|e| Error::ModelLoadFailed(format!("Failed to build BPE model: {}", e)))?;
+            Tokenizer::new(model)
+        }
+        other => {
+            return Err(Error::ModelLoadFailed(format!(
+                "Unsupported sentencepiece model type: {}",
+                other
+            )))
+        }
+    };
+
+    let mut normalizers: Vec<NormalizerWrapper> = Vec::new();
+    if !spm.precompiled_charsmap.is_empty() {
+        let precompiled = Precompiled::from(&spm.precompiled_charsmap).map_err(|e| {
+            Error::ModelLoadFailed(format!("Invalid sentencepiece charsmap: {}", e))
+        })?;
+        normalizers.push(precompiled.into());
+    }
+    if spm.remove_extra_whitespaces {
+        let replace = Replace::new(ReplacePattern::Regex(" {2,}".to_string()), " ")
+            .map_err(|e| Error::ModelLoadFailed(format!("Failed to build normalizer: {}", e)))?;
+        normalizers.push(replace.into());
+    }
+    if config.do_lower_case.unwrap_or(false) {
+        normalizers.push(Lowercase.into());
+    }
+
+    let prepend_scheme = if spm.add_dummy_prefix {
+        PrependScheme::Always
+    } else {
+        PrependScheme::Never
+    };
+    tokenizer
+        .with_normalizer(Some(Sequence::new(normalizers)))
+        .with_pre_tokenizer(Some(Metaspace::new('▁', prepend_scheme, true)))
+        .with_decoder(Some(Metaspace::new('▁', prepend_scheme, true)));
+
+    let cls =
+        TokenizerConfig::token(&config.cls_token).or(TokenizerConfig::token(&config.bos_token));
+    let sep =
+        TokenizerConfig::token(&config.sep_token).or(TokenizerConfig::token(&config.eos_token));
+
+    let mut single = Vec::new();
+    let mut special_tokens = Vec::new();
+    if let Some(cls) = cls {
+        special_tokens.push((cls.clone(), token_id(&tokenizer, &cls)?));
+        single.push(cls);
+    }
+    single.push("$A".to_string());
+    if let Some(sep) = sep {
+        special_tokens.push((sep.clone(), token_id(&tokenizer, &sep)?));
+        single.push(sep);
+    }
+    special_tokens.dedup();
+
+    let post_processor = TemplateProcessing::builder()
+        .try_single(single)
+        .and_then(|builder| {
+            builder
+                .special_tokens(special_tokens)
+                .build()
+                .map_err(|e| e.to_string())
+        })
+        .map_err(|e| Error::ModelLoadFailed(format!("Failed to build post-processor: {}", e)))?;
+    tokenizer.with_post_processor(Some(post_processor));
+
+    add_special_tokens(&mut tokenizer, config);
+    Ok(tokenizer)
+}
+
+/// The vocabulary with scores, and the id of the unknown token. With
+/// `fairseq`, ids are remapped the way fairseq does: `<s> <pad> </s> <unk>`
+/// first, then the sentencepiece pieces after its own three control tokens,
+/// shifted up by one, then `<mask>`.
+fn sentencepiece_vocab(
+    spm: &SentencePieceModel,
+    fairseq: bool,
+) -> (Vec<(String, f64)>, Option<usize>) {
+    if fairseq {
+        let mut vocab: Vec<(String, f64)> = ["<s>", "<pad>", "</s>", "<unk>"]
+            .into_iter()
+            .map(|t| (t.to_string(), 0.0))
+            .collect();
+        vocab.extend(
+            spm.pieces
+                .iter()
+                .skip(3)
+                .map(|p| (p.piece.clone(), p.score as f64)),
+        );
+        vocab.push(("<mask>".to_string(), 0.0));
+        (vocab, Some(3))
+    } else {
+        let vocab = spm
+            .pieces
+            .iter()
+            .map(|p| (p.piece.clone(), p.score as f64))
+            .collect();
+        let unk_id = spm.pieces.iter().position(|p| p.kind == PIECE_UNKNOWN);
+        (vocab, unk_id)
+    }
+}
+
+fn token_id(tokenizer: &Tokenizer, token: &str) -> Result<u32> {
+    tokenizer.token_to_id(token).ok_or_else(|| {
+        Error::ModelLoadFailed(format!("Special token '{}' not in vocabulary", token))
+    })
+}
+
+fn add_special_tokens(tokenizer: &mut Tokenizer, config: &TokenizerConfig) {
+    let tokens: Vec<AddedToken> = config
+        .special_tokens()
+        .into_iter()
+        .map(|token| AddedToken::from(token, true))
+        .collect();
+    tokenizer.add_special_tokens(&tokens);
+}
+
+/// Recover BPE merges from a sentencepiece vocabulary: every piece that can be
+/// split into two other pieces yields a merge, ranked by the merged piece's id.
+fn sentencepiece_merges(vocab: &[(String, f64)]) -> Vec<(String, String)> {
+    let ids: HashMap<&str, usize> = vocab
+        .iter()
+        .enumerate()
+        .map(|(id, (piece, _))| (piece.as_str(), id))
+        .collect();
+
+    let mut merges = Vec::new();
+    for (id, (piece, _)) in vocab.iter().enumerate() {
+        for (split, _) in piece.char_indices().skip(1) {
+            let (left, right) = piece.split_at(split);
+            if let (Some(&left_id), Some(&right_id)) = (ids.get(left), ids.get(right)) {
+                merges.push((id, left_id, right_id, left.to_string(), right.to_string()));
+            }
+        }
+    }
+
+    merges.sort();
+    merges
+        .into_iter()
+        .map(|(_, _, _, left, right)| (left, right))
+        .collect()
+}
+
+const MODEL_TYPE_UNIGRAM: u64 = 1;
+const MODEL_TYPE_BPE: u64 = 2;
+const PIECE_NORMAL: u64 = 1;
+const PIECE_UNKNOWN: u64 = 2;
+
+struct Piece {
+    piece: String,
+    score: f32,
+    kind: u64,
+}
+
+/// The parts of a sentencepiece `ModelProto` needed to rebuild the tokenizer.
+struct SentencePieceModel {
+    pieces: Vec<Piece>,
+    model_type: u64,
+    byte_fallback: bool,
+    precompiled_charsmap: Vec<u8>,
+    add_dummy_prefix: bool,
+    remove_extra_whitespaces: bool,
+}
+
+impl SentencePieceModel {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        let mut model = Self {
+            pieces: Vec::new(),
+            model_type: MODEL_TYPE_UNIGRAM,
+            byte_fallback: false,
+            precompiled_charsmap: Vec::new(),
+            add_dummy_prefix: true,
+            remove_extra_whitespaces: true,
+        };
+
+        let mut reader = ProtoReader::new(bytes);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (1, ProtoValue::Bytes(piece)) => model.pieces.push(Self::parse_piece(piece)?),
+                (2, ProtoValue::Bytes(trainer_spec)) => {
+                    let mut reader = ProtoReader::new(trainer_spec);
+                    while let Some((field, value)) = reader.next_field()? {
+                        match (field, value) {
+                            (3, ProtoValue::Varint(v)) => model.model_type = v,
+                            (35, ProtoValue::Varint(v)) => model.byte_fallback = v != 0,
+                            _ => {}
+                        }
+                    }
+                }
+                (3, ProtoValue::Bytes(normalizer_spec)) => {
+                    let mut reader = ProtoReader::new(normalizer_spec);
+                    while let Some((field, value)) = reader.next_field()? {
+                        match (field, value) {
+                            (2, ProtoValue::Bytes(v)) => model.precompiled_charsmap = v.to_vec(),
+                            (3, ProtoValue::Varint(v)) => model.add_dummy_prefix = v != 0,
+                            (4, ProtoValue::Varint(v)) => model.remove_extra_whitespaces = v != 0,
+                            _ => {}
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if model.pieces.is_empty() {
+            return Err(Error::ModelLoadFailed(
+                "Sentencepiece model has no pieces".to_string(),
+            ));
+        }
+
+        Ok(model)
+    }
+
+    fn parse_piece(bytes: &[u8]) -> Result<Piece> {
+        let mut piece = Piece {
+            piece: String::new(),
+            score: 0.0,
+            kind: PIECE_NORMAL,
+        };
+
+        let mut reader = ProtoReader::new(bytes);
+        while let Some((field, value)) = reader.next_field()? {
+            match (field, value) {
+                (1, ProtoValue::Bytes(v)) => piece.piece = String::from_utf8_lossy(v).into_owned(),
+                (2, ProtoValue::Fixed32(v)) => piece.score = f32::from_bits(v),
+                (3, ProtoValue::Varint(v)) => piece.kind = v,
+                _ => {}
+            }
+        }
+
+        Ok(piece)
+    }
+}
+
+enum ProtoValue<'a> {
+    Varint(u64),
+    Fixed64,
+    Fixed32(u32),
+    Bytes(&'a [u8]),
+}
+
+/// Minimal protobuf wire-format reader, enough to walk a sentencepiece model.
+struct ProtoReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn next_field(&mut self) -> Result<Option<(u64, ProtoValue<'a>)>> {
+        if self.pos >= self.buf.len() {
+            return Ok(None);
+        }
+
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                ProtoValue::Fixed64
+            }
+            2 => {
+                let len = self.varint()? as usize;
+                ProtoValue::Bytes(self.take(len)?)
+            }
+            5 => {
+                let bytes = self.take(4)?;
+                ProtoValue::Fixed32(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            }
+            wire_type => {
+                return Err(Error::ModelLoadFailed(format!(
+                    "Unsupported protobuf wire type {} in sentencepiece model",
+                    wire_type
+                )))
+            }
+        };
+
+        Ok(Some((key >> 3, value)))
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.take(1)?.first().unwrap_or(&0);
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::ModelLoadFailed(
+            "Malformed varint in sentencepiece model".to_string(),
+        ))
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|&end| end <= self.buf.len());
+        let end =
+            end.ok_or_else(|| Error::ModelLoadFailed("Truncated sentencepiece model".to_string()))?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                bytes.push(byte);
+                return bytes;
+            }
+            bytes.push(byte | 0x80);
+        }
+    }
+
+    fn varint_field(field: u64, value: u64) -> Vec<u8> {
+        let mut bytes = varint(field << 3);
+        bytes.extend(varint(value));
+        bytes
+    }
+
+    fn bytes_field(field: u64, value: &[u8]) -> Vec<u8> {
+        let mut bytes = varint((field << 3) | 2);
+        bytes.extend(varint(value.len() as u64));
+        bytes.extend_from_slice(value);
+        bytes
+    }
+
+    fn fixed32_field(field: u64, value: u32) -> Vec<u8> {
+        let mut bytes = varint((field << 3) | 5);
+        bytes.extend(value.to_le_bytes());
+        bytes
+    }
+
+    fn piece(piece: &str, score: f32, kind: u64) -> Vec<u8> {
+        let mut bytes = bytes_field(1, piece.as_bytes());
+        bytes.extend(fixed32_field(2, score.to_bits()));
+        bytes.extend(varint_field(3, kind));
+        bytes
+    }
+
+    const PIECE_CONTROL: u64 = 3;
+
+    /// A Unigram model in sentencepiece's own layout: `<unk> <s> </s>`, then
+    /// the normal pieces.
+    fn model_proto(model_type: u64) -> Vec<u8> {
+        let pieces = [
+            piece("<unk>", 0.0, PIECE_UNKNOWN),
+            piece("<s>", 0.0, PIECE_CONTROL),
+            piece("</s>", 0.0, PIECE_CONTROL),
+            piece("▁", -1.0, PIECE_NORMAL),
+            piece("▁cat", -2.0, PIECE_NORMAL),
+            piece("▁dog", -3.0, PIECE_NORMAL),
+        ];
+
+        let mut bytes = Vec::new();
+        for piece in &pieces {
+            bytes.extend(bytes_field(1, piece));
+        }
+        bytes.extend(bytes_field(2, &varint_field(3, model_type)));
+        let mut normalizer_spec = varint_field(3, 1);
+        normalizer_spec.extend(varint_field(4, 0));
+        bytes.extend(bytes_field(3, &normalizer_spec));
+        bytes
+    }
+
+    #[test]
+    fn reads_varints() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let bytes = varint(value);
+            assert_eq!(ProtoReader::new(&bytes).varint().unwrap(), value);
+        }
+        assert_eq!(varint(300), [0xac, 0x02]);
+
+        // Continuation bit set on the last byte
+        assert!(ProtoReader::new(&[0x80, 0x80]).varint().is_err());
+        // More than ten bytes
+        assert!(ProtoReader::new(&[0xff; 11]).varint().is_err());
+    }
+
+    #[test]
+    fn reads_fields_by_wire_type() {
+        let mut bytes = varint_field(1, 150);
+        bytes.extend(bytes_field(2, b"testing"));
+        bytes.extend(fixed32_field(3, 0xdeadbeef));
+        bytes.extend(varint(4 << 3 | 1));
+        bytes.extend([0; 8]);
+        bytes.extend(bytes_field(5, b""));
+
+        let mut reader = ProtoReader::new(&bytes);
+        assert!(matches!(
+            reader.next_field().unwrap(),
+            Some((1, ProtoValue::Varint(150)))
+        ));
+        assert!(matches!(
+            reader.next_field().unwrap(),
+            Some((2, ProtoValue::Bytes(b"testing")))
+        ));
+        assert!(matches!(
+            reader.next_field().unwrap(),
+            Some((3, ProtoValue::Fixed32(0xdeadbeef)))
+        ));
+        assert!(matches!(
+            reader.next_field().unwrap(),
+            Some((4, ProtoValue::Fixed64))
+        ));
+        assert!(matches!(
+            reader.next_field().unwrap(),
+            Some((5, ProtoValue::Bytes(b"")))
+        ));
+        assert!(reader.next_field().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_malformed_fields() {
+        // Length runs past the end of the buffer
+        let mut truncated = bytes_field(1, b"abc");
+        truncated.pop();
+        assert!(ProtoReader::new(&truncated).next_field().is_err());
+
+        // Wire type 3 (start group) is not supported
+        assert!(ProtoReader::new(&varint(1 << 3 | 3)).next_field().is_err());
+    }
+
+    #[test]
+    fn parses_sentencepiece_model() {
+        let spm = SentencePieceModel::parse(&model_proto(MODEL_TYPE_BPE)).unwrap();
+
+        let pieces: Vec<(&str, f32, u64)> = spm
+            .pieces
+            .iter()
+            .map(|p| (p.piece.as_str(), p.score, p.kind))
+            .collect();
+        assert_eq!(
+            pieces,
+            [
+                ("<unk>", 0.0, PIECE_UNKNOWN),
+                ("<s>", 0.0, PIECE_CONTROL),
+                ("</s>", 0.0, PIECE_CONTROL),
+                ("▁", -1.0, PIECE_NORMAL),
+                ("▁cat", -2.0, PIECE_NORMAL),
+                ("▁dog", -3.0, PIECE_NORMAL),
+            ]
+        );
+        assert_eq!(spm.model_type, MODEL_TYPE_BPE);
+        assert!(!spm.byte_fallback);
+        assert!(spm.add_dummy_prefix);
+        assert!(!spm.remove_extra_whitespaces);
+
+        assert!(SentencePieceModel::parse(&[]).is_err());
+    }
+
+    #[test]
+    fn maps_fairseq_ids() {
+        let spm = SentencePieceModel::parse(&model_proto(MODEL_TYPE_UNIGRAM)).unwrap();
+
+        let (vocab, unk_id) = sentencepiece_vocab(&spm, false);
+        let pieces: Vec<&str> = vocab.iter().map(|(piece, _)| piece.as_str()).collect();
+        assert_eq!(pieces, ["<unk>", "<s>", "</s>", "▁", "▁cat", "▁dog"]);
+        assert_eq!(unk_id, Some(0));
+
+        let (vocab, unk_id) = sentencepiece_vocab(&spm, true);
+        let pieces: Vec<&str> = vocab.iter().map(|(piece, _)| piece.as_str()).collect();
+        assert_eq!(
+            pieces,
+            ["<s>", "<pad>", "</s>", "<unk>", "▁", "▁cat", "▁dog", "<mask>"]
+        );
+        assert_eq!(unk_id, Some(3));
+        assert_eq!(vocab[5], ("▁cat".to_string(), -2.0));
+    }
+
+    #[test]
+    fn detects_fairseq_checkpoints() {
+        let model_dir =
+            std::env::temp_dir().join(format!("embeddy-tokenizer-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&model_dir);
+        std::fs::create_dir_all(&model_dir).unwrap();
+        std::fs::write(
+            model_dir.join(FAIRSEQ_SENTENCEPIECE_FILE),
+            model_proto(MODEL_TYPE_UNIGRAM),
+        )
+        .unwrap();
+
+        // No tokenizer_class, as in many XLM-R derived checkpoints
+        let config = TokenizerConfig::default();
+        assert!(is_fairseq(&model_dir, FAIRSEQ_SENTENCEPIECE_FILE, &config));
+        assert!(!is_fairseq(&model_dir, "spiece.model", &config));
+
+        std::fs::write(
+            model_dir.join("config.json"),
+            r#"{"model_type": "xlm-roberta"}"#,
+        )
+        .unwrap();
+        assert!(is_fairseq(&model_dir, "spiece.model", &config));
+
+        let tokenizer = load(&model_dir).unwrap();
+        let encoding = tokenizer.encode("cat dog", false).unwrap();
+        assert_eq!(encoding.get_ids(), [5, 6]);
+
+        std::fs::remove_dir_all(&model_dir).unwrap();
+    }
+}
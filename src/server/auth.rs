@@ -0,0 +1,152 @@
+use super::policy::KeyPolicy;
+use super::AppState;
+use crate::error::{Error, Result};
+use axum::{
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+
+/// Label given to keys passed via `--api-key` or `EMBEDDY_API_KEYS`.
+const DEFAULT_LABEL: &str = "default";
+
+#[derive(Clone, Deserialize)]
+pub struct ApiKey {
+    pub key: String,
+    /// Name reported in logs and metrics in place of the key itself.
+    #[serde(default = "default_label")]
+    pub label: String,
+    #[serde(flatten)]
+    pub policy: KeyPolicy,
+}
+
+fn default_label() -> String {
+    DEFAULT_LABEL.to_string()
+}
+
+#[derive(Deserialize)]
+struct KeysFile {
+    #[serde(default)]
+    keys: Vec<ApiKey>,
+}
+
+/// Bearer tokens accepted by the server. Authentication is disabled when no
+/// keys are configured.
+#[derive(Clone, Default)]
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+}
+
+// Never print the keys themselves
+impl fmt::Debug for ApiKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.keys.iter().map(|k| &k.label))
+            .finish()
+    }
+}
+
+impl ApiKeys {
+    pub fn new(keys: Vec<String>) -> Self {
+        Self {
+            keys: keys
+                .into_iter()
+                .filter(|key| !key.is_empty())
+                .map(|key| ApiKey {
+                    key,
+                    label: default_label(),
+                    policy: KeyPolicy::default(),
+                })
+                .collect(),
+        }
+    }
+
+    /// Add the keys listed in a TOML file of `[[keys]]` tables with `key`
+    /// and optional `label` fields, plus the limits in [`KeyPolicy`].
+    pub fn load_file(mut self, path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("Failed to read keys file {:?}: {}", path, e)))?;
+        let mut file: KeysFile = toml::from_str(&content)?;
+
+        if let Some(key) = file.keys.iter().find(|k| k.key.is_empty()) {
+            return Err(Error::Config(format!(
+                "Empty key for '{}' in {:?}",
+                key.label, path
+            )));
+        }
+
+        for key in &mut file.keys {
+            key.policy
+                .init()
+                .map_err(|e| Error::Config(format!("Key '{}' in {:?}: {}", key.label, path, e)))?;
+        }
+
+        self.keys.extend(file.keys);
+        Ok(self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn find(&self, token: &str) -> Option<&ApiKey> {
+        self.keys
+            .iter()
+            .find(|k| constant_time_eq(k.key.as_bytes(), token.as_bytes()))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Label of the key that authenticated a request, available to handlers as a
+/// request extension and to outer layers as a response extension.
+#[derive(Debug, Clone)]
+pub struct KeyLabel(pub String);
+
+/// Middleware requiring `Authorization: Bearer <key>` when API keys are
+/// configured.
+pub async fn require_api_key(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let keys = &state.options().api_keys;
+    if keys.is_empty() {
+        return next.run(request).await;
+    }
+
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+
+    let key = match token {
+        None => {
+            return Error::Unauthorized("Missing bearer token".to_string()).into_response();
+        }
+        Some(token) => match keys.find(token) {
+            Some(key) => key.clone(),
+            None => return Error::Unauthorized("Invalid API key".to_string()).into_response(),
+        },
+    };
+
+    let label = KeyLabel(key.label.clone());
+    request.extensions_mut().insert(label.clone());
+    request.extensions_mut().insert(key);
+    tracing::Span::current().record("key", label.0.as_str());
+    let mut response = next.run(request).await;
+    response.extensions_mut().insert(label);
+    response
+}
@@ -0,0 +1,299 @@
+use super::AppState;
+use crate::embedder::{EmbedOptions, Embedder};
+use crate::error::{Error, Result};
+use crate::metrics;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio::time::Instant;
+
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    /// Upper bound on tokens per coalesced batch, as estimated by
+    /// `Embedder::estimate_tokens`. A single request larger than this still
+    /// runs, on its own.
+    pub max_batch_tokens: usize,
+    pub max_batch_requests: usize,
+    /// How long the first request in a batch waits for others to join.
+    pub batch_timeout: Duration,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            max_batch_tokens: 8192,
+            max_batch_requests: 32,
+            batch_timeout: Duration::from_millis(5),
+        }
+    }
+}
+
+struct Job {
+    texts: Vec<String>,
+    tokens: usize,
+    options: EmbedOptions,
+    /// Embeddings and the tokens they actually used
+    respond: oneshot::Sender<Result<(Vec<Vec<f32>>, usize)>>,
+}
+
+/// Per-model queue that coalesces concurrent requests into a single
+/// inference call and fans the results back out.
+///
+/// The batching task exits once the `Batcher` is dropped (e.g. when the
+/// model is evicted) and the queue has drained.
+pub struct Batcher {
+    tx: mpsc::UnboundedSender<Job>,
+    embedder: Arc<Embedder>,
+    depth: Arc<AtomicUsize>,
+    closed: Arc<Notify>,
+}
+
+impl Batcher {
+    pub fn spawn(state: AppState, embedder: Arc<Embedder>, options: BatchOptions) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let depth = Arc::new(AtomicUsize::new(0));
+        let closed = Arc::new(Notify::new());
+
+        tokio::spawn(run(
+            state,
+            embedder.clone(),
+            rx,
+            depth.clone(),
+            closed.clone(),
+            options,
+        ));
+
+        Self {
+            tx,
+            embedder,
+            depth,
+            closed,
+        }
+    }
+
+    /// Stop batching: requests still queued, and any sent afterwards, fail
+    /// with [`Error::Overloaded`]. Batches already running complete.
+    pub fn close(&self) {
+        self.closed.notify_one();
+    }
+
+    /// Number of requests waiting to be picked up into a batch.
+    pub fn queue_depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    /// Queue `texts`, whose size in tokens the caller has estimated, and
+    /// wait for their embeddings and the number of tokens they used.
+    pub async fn embed(
+        &self,
+        texts: Vec<String>,
+        tokens: usize,
+        options: EmbedOptions,
+    ) -> Result<(Vec<Vec<f32>>, usize)> {
+        let (respond, response) = oneshot::channel();
+
+        let queue_depth = metrics::BATCH_QUEUE_DEPTH.with_label_values(&[self.embedder.name()]);
+        self.depth.fetch_add(1, Ordering::SeqCst);
+        queue_depth.inc();
+
+        let job = Job {
+            texts,
+            tokens,
+            options,
+            respond,
+        };
+        if self.tx.send(job).is_err() {
+            self.depth.fetch_sub(1, Ordering::SeqCst);
+            queue_depth.dec();
+            return Err(Error::Overloaded("Batch queue closed".to_string()));
+        }
+
+        response
+            .await
+            .map_err(|_| Error::Embedding("Batch was dropped".to_string()))?
+    }
+}
+
+async fn run(
+    state: AppState,
+    embedder: Arc<Embedder>,
+    mut rx: mpsc::UnboundedReceiver<Job>,
+    depth: Arc<AtomicUsize>,
+    closed: Arc<Notify>,
+    options: BatchOptions,
+) {
+    let queue_depth = metrics::BATCH_QUEUE_DEPTH.with_label_values(&[embedder.name()]);
+    let take = |job: Job| {
+        depth.fetch_sub(1, Ordering::SeqCst);
+        queue_depth.dec();
+        job
+    };
+
+    let mut pending: Option<Job> = None;
+    let mut closing = false;
+
+    loop {
+        let first = match pending.take() {
+            Some(job) => job,
+            None if closing => break,
+            None => tokio::select! {
+                job = rx.recv() => match job {
+                    Some(job) => take(job),
+                    None => break,
+                },
+                _ = closed.notified() => break,
+            },
+        };
+
+        let deadline = Instant::now() + options.batch_timeout;
+        let mut tokens = first.tokens;
+        let mut batch = vec![first];
+
+        // Once closed, run what has been collected right away
+        while !closing
+            && batch.len() < options.max_batch_requests
+            && tokens < options.max_batch_tokens
+        {
+            tokio::select! {
+                job = tokio::time::timeout_at(deadline, rx.recv()) => match job {
+                    Ok(Some(job)) => {
+                        let job = take(job);
+                        if tokens + job.tokens > options.max_batch_tokens
+                            || job.options != batch[0].options
+                        {
+                            pending = Some(job);
+                            break;
+                        }
+                        tokens += job.tokens;
+                        batch.push(job);
+                    }
+                    Ok(None) | Err(_) => break,
+                },
+                _ = closed.notified() => closing = true,
+            }
+        }
+
+        // Run the batch in its own task so the next one can be collected
+        // while this one is computing; the worker pool bounds concurrency.
+        tokio::spawn(execute(state.clone(), embedder.clone(), batch));
+    }
+
+    rx.close();
+    while let Ok(job) = rx.try_recv() {
+        let _ = take(job).respond.send(Err(Error::Overloaded(
+            "Server is shutting down".to_string(),
+        )));
+    }
+}
+
+async fn execute(state: AppState, embedder: Arc<Embedder>, batch: Vec<Job>) {
+    // Skip requests whose caller has gone away, e.g. cancelled WebSocket
+    // requests
+    let batch: Vec<Job> = batch
+        .into_iter()
+        .filter(|job| !job.respond.is_closed())
+        .collect();
+    if batch.is_empty() {
+        return;
+    }
+
+    let sizes: Vec<usize> = batch.iter().map(|job| job.texts.len()).collect();
+    // Every job in a batch has the same options
+    let options = batch[0].options.clone();
+    let (texts, responders): (Vec<Vec<String>>, Vec<_>) = batch
+        .into_iter()
+        .map(|job| (job.texts, job.respond))
+        .unzip();
+    let texts: Arc<Vec<String>> = Arc::new(texts.into_iter().flatten().collect());
+
+    tracing::debug!(
+        "Running batch of {} requests ({} texts)",
+        responders.len(),
+        texts.len()
+    );
+
+    let result = {
+        let embedder = embedder.clone();
+        let texts = texts.clone();
+        let options = options.clone();
+        state
+            .run_blocking(move || embedder.embed(&texts, &options))
+            .await
+    };
+
+    match result {
+        Ok(embedded) => {
+            let mut embeddings = embedded.embeddings.into_iter();
+            let mut tokens = embedded.tokens.into_iter();
+            for (respond, size) in responders.into_iter().zip(sizes) {
+                let job_embeddings = embeddings.by_ref().take(size).collect();
+                let job_tokens = tokens.by_ref().take(size).sum();
+                let _ = respond.send(Ok((job_embeddings, job_tokens)));
+            }
+        }
+        Err(e) if responders.len() == 1 => {
+            if let Some(respond) = responders.into_iter().next() {
+                let _ = respond.send(Err(e));
+            }
+        }
+        Err(e) => {
+            // One request's input mustn't fail the requests batched with it:
+            // run each on its own so every request gets its own result
+            tracing::debug!("Batch failed ({}), retrying its requests one by one", e);
+            let mut start = 0;
+            for (respond, size) in responders.into_iter().zip(sizes) {
+                let range = start..start + size;
+                start += size;
+                if respond.is_closed() {
+                    continue;
+                }
+
+                let embedder = embedder.clone();
+                let texts = texts.clone();
+                let options = options.clone();
+                let result = state
+                    .run_blocking(move || embedder.embed(&texts[range], &options))
+                    .await
+                    .map(|embedded| (embedded.embeddings, embedded.tokens.into_iter().sum()));
+                let _ = respond.send(result);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::fixture;
+    use crate::server::ServerOptions;
+    use candle_core::Device;
+
+    #[tokio::test]
+    async fn failed_request_does_not_fail_its_batch() {
+        let state = AppState::new(
+            fixture("batcher-isolation"),
+            Device::Cpu,
+            ServerOptions::default(),
+        );
+        let embedder = state.get_or_load_embedder("tiny", None).await.unwrap();
+        let batcher = Batcher::spawn(
+            state,
+            embedder,
+            BatchOptions {
+                batch_timeout: Duration::from_millis(100),
+                ..Default::default()
+            },
+        );
+
+        let texts = |text: &str| vec![text.to_string()];
+        let (ok, failed) = tokio::join!(
+            batcher.embed(texts("cat"), 3, EmbedOptions::default()),
+            batcher.embed(texts("broken"), 3, EmbedOptions::default()),
+        );
+        let (embeddings, tokens) = ok.unwrap();
+        assert_eq!(embeddings.len(), 1);
+        assert_eq!(tokens, 3);
+        assert!(failed.is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use crate::error::{Error, Result};
+use axum::{
+    body::Bytes,
+    extract::{rejection::BytesRejection, FromRequest, Request},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub const MSGPACK: &str = "application/msgpack";
+
+/// Raw little-endian matrix of f32 embeddings:
+///
+/// | Offset | Size | Field                               |
+/// |--------|------|-------------------------------------|
+/// | 0      | 4    | magic `EMBF`                        |
+/// | 4      | 4    | format version, `u32` (1)           |
+/// | 8      | 4    | rows (one per input), `u32`         |
+/// | 12     | 4    | dimension, `u32`                    |
+/// | 16     | ...  | `rows * dimension` `f32`, row-major |
+pub const F32_MATRIX: &str = "application/x-embeddy-f32";
+
+const F32_MAGIC: &[u8; 4] = b"EMBF";
+const F32_VERSION: u32 = 1;
+
+fn is_msgpack(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| {
+            let mime = mime.trim();
+            mime.eq_ignore_ascii_case(MSGPACK) || mime.eq_ignore_ascii_case("application/x-msgpack")
+        })
+}
+
+/// Decode a request body as MessagePack or JSON, according to its
+/// `Content-Type`.
+pub fn decode<T: DeserializeOwned>(headers: &HeaderMap, bytes: &[u8]) -> Result<T> {
+    if is_msgpack(headers) {
+        rmp_serde::from_slice(bytes)
+            .map_err(|e| Error::InvalidInput(format!("Failed to decode MessagePack body: {}", e)))
+    } else {
+        serde_json::from_slice(bytes)
+            .map_err(|e| Error::InvalidInput(format!("Failed to parse JSON body: {}", e)))
+    }
+}
+
+/// Request body extractor accepting JSON, or MessagePack when sent with
+/// `Content-Type: application/msgpack`.
+pub struct Payload<T>(pub T);
+
+impl<T, S> FromRequest<S> for Payload<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = Error;
+
+    async fn from_request(request: Request, state: &S) -> Result<Self> {
+        if !is_msgpack(request.headers()) {
+            return match Json::<T>::from_request(request, state).await {
+                Ok(Json(value)) => Ok(Payload(value)),
+                Err(rejection) if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                    Err(Error::PayloadTooLarge(rejection.body_text()))
+                }
+                Err(rejection) => Err(Error::InvalidInput(rejection.body_text())),
+            };
+        }
+
+        let headers = request.headers().clone();
+        let bytes =
+            Bytes::from_request(request, state)
+                .await
+                .map_err(|rejection: BytesRejection| match rejection.status() {
+                    StatusCode::PAYLOAD_TOO_LARGE => Error::PayloadTooLarge(rejection.body_text()),
+                    _ => Error::InvalidInput(rejection.body_text()),
+                })?;
+        decode(&headers, &bytes).map(Payload)
+    }
+}
+
+/// Response encoding chosen from the `Accept` header. Anything other than
+/// MessagePack or the raw f32 matrix gets JSON.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseFormat {
+    Json,
+    MsgPack,
+    F32Matrix,
+}
+
+impl ResponseFormat {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let Some(accept) = headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return Self::Json;
+        };
+
+        // Highest quality first; ties keep the client's order
+        let mut ranges: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|range| {
+                let mut parts = range.split(';');
+                let mime = parts.next().unwrap_or_default().trim();
+                let quality = parts
+                    .filter_map(|param| param.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (mime, quality)
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges
+            .into_iter()
+            .find_map(|(mime, _)| match mime.to_ascii_lowercase().as_str() {
+                "application/json" | "application/*" | "*/*" => Some(Self::Json),
+                MSGPACK | "application/x-msgpack" => Some(Self::MsgPack),
+                F32_MATRIX => Some(Self::F32Matrix),
+                _ => None,
+            })
+            .unwrap_or(Self::Json)
+    }
+
+    /// Serialize `value` as JSON or MessagePack. The f32 matrix only carries
+    /// embeddings, see [`f32_matrix`].
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Response> {
+        let response = match self {
+            Self::Json | Self::F32Matrix => Json(value).into_response(),
+            Self::MsgPack => {
+                let body = rmp_serde::to_vec_named(value).map_err(|e| {
+                    Error::Serialization(format!("Failed to encode MessagePack: {}", e))
+                })?;
+                (
+                    [(header::CONTENT_TYPE, HeaderValue::from_static(MSGPACK))],
+                    body,
+                )
+                    .into_response()
+            }
+        };
+        Ok(vary_accept(response))
+    }
+}
+
+/// Encode embeddings as an [`F32_MATRIX`] response.
+pub fn f32_matrix(embeddings: &[Vec<f32>], dimension: usize) -> Response {
+    let mut body = Vec::with_capacity(16 + embeddings.len() * dimension * 4);
+    body.extend_from_slice(F32_MAGIC);
+    body.extend_from_slice(&F32_VERSION.to_le_bytes());
+    body.extend_from_slice(&(embeddings.len() as u32).to_le_bytes());
+    body.extend_from_slice(&(dimension as u32).to_le_bytes());
+    for value in embeddings.iter().flatten() {
+        body.extend_from_slice(&value.to_le_bytes());
+    }
+
+    vary_accept(
+        (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(F32_MATRIX))],
+            body,
+        )
+            .into_response(),
+    )
+}
+
+fn vary_accept(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .append(header::VARY, HeaderValue::from_static("accept"));
+    response
+}
@@ -0,0 +1,568 @@
+//! gRPC API, served on `--grpc-port` alongside the HTTP server. Calls share
+//! the HTTP server's [`AppState`] (and so its models and batching queues),
+//! API keys, per-key policies and request limits.
+
+use super::auth::ApiKey;
+use super::state::ModelState;
+use super::{health_status, AppState, KeepAlive};
+use crate::embedder::{EmbedOptions, InputType};
+use crate::error::{Error, Result};
+use crate::model::Pooling;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::metadata::MetadataValue;
+use tonic::transport::server::TcpIncoming;
+use tonic::{Code, Request, Response, Status, Streaming};
+use tower_http::trace::TraceLayer;
+
+pub mod proto {
+    tonic::include_proto!("embeddy.v1");
+}
+
+use proto::embeddy_server::{Embeddy, EmbeddyServer};
+
+/// Requests from one `EmbedStream` call processed concurrently; further
+/// messages aren't read until one finishes.
+const STREAM_CONCURRENCY: usize = 32;
+
+impl From<Error> for Status {
+    fn from(error: Error) -> Self {
+        let code = match &error {
+            Error::ModelNotFound(_) => Code::NotFound,
+            Error::InvalidInput(_) => Code::InvalidArgument,
+            Error::PayloadTooLarge(_) => Code::InvalidArgument,
+            Error::Overloaded(_) => Code::Unavailable,
+            Error::Unauthorized(_) => Code::Unauthenticated,
+            Error::Forbidden(_) => Code::PermissionDenied,
+            Error::RateLimited(_) => Code::ResourceExhausted,
+            Error::ModelLoadFailed(_) | Error::Embedding(_) => Code::Internal,
+            _ => {
+                tracing::error!("Internal error: {}", error);
+                return Status::internal("Internal server error");
+            }
+        };
+        Status::new(code, error.to_string())
+    }
+}
+
+#[derive(Clone)]
+pub struct GrpcService {
+    state: AppState,
+}
+
+impl GrpcService {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+
+    /// The key a call authenticated with, from its `authorization: Bearer`
+    /// metadata. `None` when authentication is disabled.
+    fn authenticate<T>(&self, request: &Request<T>) -> std::result::Result<Option<ApiKey>, Status> {
+        let keys = &self.state.options().api_keys;
+        if keys.is_empty() {
+            return Ok(None);
+        }
+
+        let token = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim);
+
+        match token {
+            None => Err(Error::Unauthorized("Missing bearer token".to_string()).into()),
+            Some(token) => match keys.find(token) {
+                Some(key) => Ok(Some(key.clone())),
+                None => Err(Error::Unauthorized("Invalid API key".to_string()).into()),
+            },
+        }
+    }
+
+    /// Admit a call to `model` against the key's policy and the server's
+    /// in-flight limit, as the HTTP middleware does.
+    fn admit(
+        &self,
+        key: Option<&ApiKey>,
+        model: &str,
+    ) -> std::result::Result<OwnedSemaphorePermit, Status> {
+        if let Some(key) = key {
+            if let Err((error, wait)) = key.policy.admit(&key.label, Some(model)) {
+                let mut status = Status::from(error);
+                if let Some(wait) = wait {
+                    let secs = wait.as_secs_f64().ceil().max(1.0) as u64;
+                    status
+                        .metadata_mut()
+                        .insert("retry-after", MetadataValue::from(secs));
+                }
+                return Err(status);
+            }
+        }
+
+        if self.state.is_shutting_down() {
+            return Err(Error::Overloaded("Server is shutting down".to_string()).into());
+        }
+        self.state.try_acquire_request().ok_or_else(|| {
+            Error::Overloaded(format!(
+                "{} requests already in flight",
+                self.state.options().limits.max_in_flight
+            ))
+            .into()
+        })
+    }
+
+    async fn embed_request(
+        &self,
+        key: Option<&ApiKey>,
+        request: proto::EmbedRequest,
+    ) -> std::result::Result<proto::EmbedResponse, Status> {
+        let _permit = self.admit(key, &request.model)?;
+        self.state.options().limits.check_inputs(&request.input)?;
+
+        let options = EmbedOptions {
+            input_type: input_type(request.input_type()),
+            pooling: pooling(request.pooling()),
+            normalize: request.normalize,
+            max_seq_length: request.max_seq_length.map(|n| n as usize),
+            dimensions: request.dimensions.map(|n| n as usize),
+        };
+        let keep_alive = request.keep_alive.map(KeepAlive::from_secs);
+
+        let result = self
+            .state
+            .embed(&request.model, request.input, options, keep_alive)
+            .await?;
+        if let Some(key) = key {
+            key.policy.charge_tokens(result.tokens);
+        }
+
+        Ok(proto::EmbedResponse {
+            model: request.model,
+            dimension: result.dimension as u32,
+            embeddings: result
+                .embeddings
+                .into_iter()
+                .map(|values| proto::Embedding { values })
+                .collect(),
+            tokens: result.tokens as u64,
+        })
+    }
+}
+
+fn input_type(value: proto::InputType) -> Option<InputType> {
+    match value {
+        proto::InputType::Unspecified => None,
+        proto::InputType::Query => Some(InputType::Query),
+        proto::InputType::Document => Some(InputType::Document),
+    }
+}
+
+fn pooling(value: proto::Pooling) -> Option<Pooling> {
+    match value {
+        proto::Pooling::Unspecified => None,
+        proto::Pooling::Mean => Some(Pooling::Mean),
+        proto::Pooling::Cls => Some(Pooling::Cls),
+        proto::Pooling::Max => Some(Pooling::Max),
+        proto::Pooling::Last => Some(Pooling::Last),
+    }
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+type EmbedStreamResult = std::result::Result<proto::EmbedStreamResponse, Status>;
+
+#[tonic::async_trait]
+impl Embeddy for GrpcService {
+    async fn embed(
+        &self,
+        request: Request<proto::EmbedRequest>,
+    ) -> std::result::Result<Response<proto::EmbedResponse>, Status> {
+        let key = self.authenticate(&request)?;
+        let response = self
+            .embed_request(key.as_ref(), request.into_inner())
+            .await?;
+        Ok(Response::new(response))
+    }
+
+    type EmbedStreamStream = Pin<Box<dyn Stream<Item = EmbedStreamResult> + Send>>;
+
+    async fn embed_stream(
+        &self,
+        request: Request<Streaming<proto::EmbedStreamRequest>>,
+    ) -> std::result::Result<Response<Self::EmbedStreamStream>, Status> {
+        let key = self.authenticate(&request)?;
+        let mut incoming = request.into_inner();
+        let (tx, rx) = mpsc::channel(STREAM_CONCURRENCY);
+        let service = self.clone();
+
+        tokio::spawn(async move {
+            let slots = Arc::new(Semaphore::new(STREAM_CONCURRENCY));
+            loop {
+                let Ok(slot) = slots.clone().acquire_owned().await else {
+                    break;
+                };
+                let message = match incoming.message().await {
+                    Ok(Some(message)) => message,
+                    Ok(None) => break,
+                    Err(status) => {
+                        let _ = tx.send(Err(status)).await;
+                        break;
+                    }
+                };
+
+                // Requests run concurrently so they can share batches
+                let service = service.clone();
+                let key = key.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let request = message.request.unwrap_or_default();
+                    let result = match service.embed_request(key.as_ref(), request).await {
+                        Ok(response) => proto::embed_stream_response::Result::Response(response),
+                        Err(status) => proto::embed_stream_response::Result::Error(proto::Error {
+                            code: status.code() as i32,
+                            message: status.message().to_string(),
+                        }),
+                    };
+                    let _ = tx
+                        .send(Ok(proto::EmbedStreamResponse {
+                            id: message.id,
+                            result: Some(result),
+                        }))
+                        .await;
+                    drop(slot);
+                });
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn rerank(
+        &self,
+        request: Request<proto::RerankRequest>,
+    ) -> std::result::Result<Response<proto::RerankResponse>, Status> {
+        let key = self.authenticate(&request)?;
+        let request = request.into_inner();
+        let _permit = self.admit(key.as_ref(), &request.model)?;
+
+        let limits = &self.state.options().limits;
+        let query = vec![request.query];
+        limits.check_inputs(&query)?;
+        limits.check_inputs(&request.documents)?;
+
+        let keep_alive = request.keep_alive.map(KeepAlive::from_secs);
+        let options = |input_type| EmbedOptions {
+            input_type: Some(input_type),
+            normalize: Some(true),
+            ..Default::default()
+        };
+        let (query, documents) = tokio::try_join!(
+            self.state
+                .embed(&request.model, query, options(InputType::Query), keep_alive),
+            self.state.embed(
+                &request.model,
+                request.documents,
+                options(InputType::Document),
+                keep_alive
+            ),
+        )?;
+        let tokens = query.tokens + documents.tokens;
+        if let Some(key) = &key {
+            key.policy.charge_tokens(tokens);
+        }
+
+        // Embeddings are normalized, so the dot product is the cosine
+        let mut results: Vec<proto::RerankResult> = documents
+            .embeddings
+            .iter()
+            .enumerate()
+            .map(|(index, document)| proto::RerankResult {
+                index: index as u32,
+                score: dot(&query.embeddings[0], document),
+            })
+            .collect();
+        results.sort_by(|a, b| b.score.total_cmp(&a.score));
+        if let Some(top_n) = request.top_n {
+            results.truncate(top_n as usize);
+        }
+
+        Ok(Response::new(proto::RerankResponse {
+            model: request.model,
+            results,
+            tokens: tokens as u64,
+        }))
+    }
+
+    async fn list_models(
+        &self,
+        request: Request<proto::ListModelsRequest>,
+    ) -> std::result::Result<Response<proto::ListModelsResponse>, Status> {
+        let key = self.authenticate(&request)?;
+
+        let models = self
+            .state
+            .list_models()
+            .await?
+            .into_iter()
+            // Keys only see the models they may use
+            .filter(|model| {
+                key.as_ref()
+                    .is_none_or(|key| key.policy.allows(&model.name))
+            })
+            .map(|model| proto::ModelStatus {
+                name: model.name,
+                repository: model.repository,
+                state: match model.state {
+                    ModelState::Loaded => "loaded",
+                    ModelState::Loading => "loading",
+                    ModelState::Unloaded => "unloaded",
+                }
+                .to_string(),
+                device: model.device,
+                loaded_at: model.loaded_at,
+                memory_bytes: model.memory_bytes,
+                last_error: model.last_error,
+                queue_depth: model.queue_depth as u64,
+            })
+            .collect();
+
+        Ok(Response::new(proto::ListModelsResponse { models }))
+    }
+
+    async fn health(
+        &self,
+        _request: Request<proto::HealthRequest>,
+    ) -> std::result::Result<Response<proto::HealthResponse>, Status> {
+        let models = self.state.list_models().await?;
+
+        Ok(Response::new(proto::HealthResponse {
+            status: health_status(&self.state, &models).await.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_seconds: self.state.uptime().as_secs(),
+            loaded_models: self.state.loaded_models().await,
+        }))
+    }
+}
+
+/// Bind the gRPC listener and return the server future, which stops
+/// accepting calls once shutdown begins.
+pub async fn bind(
+    host: &str,
+    port: u16,
+    state: AppState,
+) -> Result<impl Future<Output = Result<()>>> {
+    let addr = format!("{}:{}", host, port);
+    let listener = tokio::net::TcpListener::bind(&addr)
+        .await
+        .map_err(|e| Error::Config(format!("Failed to bind to {}: {}", addr, e)))?;
+
+    tracing::info!("Starting gRPC server on {}", addr);
+    Ok(serve(listener, state))
+}
+
+async fn serve(listener: tokio::net::TcpListener, state: AppState) -> Result<()> {
+    let service = EmbeddyServer::new(GrpcService::new(state.clone()))
+        .max_decoding_message_size(state.options().limits.max_request_bytes);
+
+    tonic::transport::Server::builder()
+        .layer(TraceLayer::new_for_grpc())
+        .add_service(service)
+        .serve_with_incoming_shutdown(TcpIncoming::from(listener), async move {
+            state.shutdown_requested().await
+        })
+        .await
+        .map_err(|e| Error::Config(format!("gRPC server error: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::embeddy_client::EmbeddyClient;
+    use super::proto::{self, embed_stream_response::Result as StreamResult};
+    use super::*;
+    use crate::server::auth::ApiKeys;
+    use crate::server::testing::{add_model, fixture};
+    use crate::server::ServerOptions;
+    use candle_core::Device;
+    use tonic::service::interceptor::InterceptedService;
+    use tonic::transport::Channel;
+
+    const KEY: &str = "test-key";
+    /// May only use `tiny`
+    const RESTRICTED_KEY: &str = "restricted-key";
+
+    type Client = EmbeddyClient<
+        InterceptedService<Channel, fn(Request<()>) -> std::result::Result<Request<()>, Status>>,
+    >;
+
+    async fn start(name: &str) -> (Client, Client, AppState) {
+        let config = fixture(&format!("grpc-{}", name));
+        add_model(&config, "other");
+        let keys_file = config.data_dir.join("keys.toml");
+        std::fs::write(
+            &keys_file,
+            format!(
+                "[[keys]]\nkey = \"{}\"\nmodels = [\"tiny\"]\n",
+                RESTRICTED_KEY
+            ),
+        )
+        .unwrap();
+
+        let options = ServerOptions {
+            api_keys: ApiKeys::new(vec![KEY.to_string()])
+                .load_file(&keys_file)
+                .unwrap(),
+            batch: crate::server::batcher::BatchOptions {
+                batch_timeout: std::time::Duration::from_millis(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let state = AppState::new(config, Device::Cpu, options);
+        state.preload().await.unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, state.clone()));
+
+        let channel = Channel::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let authorized: fn(Request<()>) -> std::result::Result<Request<()>, Status> =
+            |mut request| {
+                let value = format!("Bearer {}", KEY).parse().unwrap();
+                request.metadata_mut().insert("authorization", value);
+                Ok(request)
+            };
+        let anonymous: fn(Request<()>) -> std::result::Result<Request<()>, Status> = Ok;
+
+        (
+            EmbeddyClient::with_interceptor(channel.clone(), authorized),
+            EmbeddyClient::with_interceptor(channel, anonymous),
+            state,
+        )
+    }
+
+    fn embed_request(input: &[&str]) -> proto::EmbedRequest {
+        proto::EmbedRequest {
+            model: "tiny".to_string(),
+            input: input.iter().map(|s| s.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_embeddings_over_grpc() {
+        let (mut client, mut anonymous, state) = start("embed").await;
+
+        let health = anonymous.health(proto::HealthRequest {}).await.unwrap();
+        assert_eq!(health.into_inner().status, "ok");
+
+        let denied = anonymous.embed(embed_request(&["cat"])).await.unwrap_err();
+        assert_eq!(denied.code(), Code::Unauthenticated);
+
+        let response = client
+            .embed(proto::EmbedRequest {
+                dimensions: Some(4),
+                ..embed_request(&["cat", "dog"])
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(response.dimension, 4);
+        assert_eq!(response.embeddings.len(), 2);
+        assert!(response.embeddings.iter().all(|e| e.values.len() == 4));
+        assert!(response.tokens > 0);
+
+        let invalid = client
+            .embed(proto::EmbedRequest {
+                dimensions: Some(64),
+                ..embed_request(&["cat"])
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(invalid.code(), Code::InvalidArgument);
+
+        let models = client
+            .list_models(proto::ListModelsRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .models;
+        let names: Vec<&str> = models.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["other", "tiny"]);
+
+        let mut request = Request::new(proto::ListModelsRequest {});
+        let value = format!("Bearer {}", RESTRICTED_KEY).parse().unwrap();
+        request.metadata_mut().insert("authorization", value);
+        let models = anonymous
+            .list_models(request)
+            .await
+            .unwrap()
+            .into_inner()
+            .models;
+        let names: Vec<&str> = models.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["tiny"]);
+
+        state.begin_shutdown();
+    }
+
+    #[tokio::test]
+    async fn streams_and_reranks() {
+        let (mut client, _, state) = start("stream").await;
+
+        let requests = vec![
+            proto::EmbedStreamRequest {
+                id: "ok".to_string(),
+                request: Some(embed_request(&["cat"])),
+            },
+            proto::EmbedStreamRequest {
+                id: "missing".to_string(),
+                request: Some(proto::EmbedRequest {
+                    model: "missing".to_string(),
+                    ..embed_request(&["cat"])
+                }),
+            },
+        ];
+        let mut stream = client
+            .embed_stream(tokio_stream::iter(requests))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let mut responses = std::collections::HashMap::new();
+        while let Some(response) = stream.message().await.unwrap() {
+            responses.insert(response.id, response.result.unwrap());
+        }
+        assert!(matches!(responses["ok"], StreamResult::Response(_)));
+        match &responses["missing"] {
+            StreamResult::Error(error) => assert_eq!(error.code, Code::NotFound as i32),
+            other => panic!("expected an error, got {:?}", other),
+        }
+
+        let results = client
+            .rerank(proto::RerankRequest {
+                model: "tiny".to_string(),
+                query: "dog".to_string(),
+                documents: vec!["car".to_string(), "dog".to_string(), "cat".to_string()],
+                top_n: Some(2),
+                keep_alive: None,
+            })
+            .await
+            .unwrap()
+            .into_inner()
+            .results;
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].index, 1);
+        assert!(results[0].score > results[1].score);
+
+        state.begin_shutdown();
+    }
+}
@@ -0,0 +1,94 @@
+use super::AppState;
+use crate::error::{Error, Result};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tokio::sync::OwnedSemaphorePermit;
+
+/// Seconds clients are asked to wait before retrying a rejected request.
+pub const RETRY_AFTER_SECS: u64 = 1;
+
+#[derive(Debug, Clone)]
+pub struct RequestLimits {
+    pub max_inputs_per_request: usize,
+    /// Maximum length of a single input, in characters.
+    pub max_input_chars: usize,
+    /// Maximum size of a request body; larger bodies are rejected before
+    /// they are parsed.
+    pub max_request_bytes: usize,
+    /// Requests processed concurrently before new ones are turned away.
+    pub max_in_flight: usize,
+}
+
+impl Default for RequestLimits {
+    fn default() -> Self {
+        Self {
+            max_inputs_per_request: 1024,
+            max_input_chars: 100_000,
+            max_request_bytes: 10 * 1024 * 1024,
+            max_in_flight: 256,
+        }
+    }
+}
+
+impl RequestLimits {
+    pub fn check_inputs(&self, input: &[String]) -> Result<()> {
+        if input.is_empty() {
+            return Err(Error::InvalidInput("Input cannot be empty".to_string()));
+        }
+
+        if input.len() > self.max_inputs_per_request {
+            return Err(Error::InvalidInput(format!(
+                "Too many inputs: {} (maximum is {})",
+                input.len(),
+                self.max_inputs_per_request
+            )));
+        }
+
+        for (i, text) in input.iter().enumerate() {
+            // Byte length bounds the char count, so skip counting short inputs
+            if text.len() > self.max_input_chars {
+                let chars = text.chars().count();
+                if chars > self.max_input_chars {
+                    return Err(Error::InvalidInput(format!(
+                        "Input {} is too long: {} characters (maximum is {})",
+                        i, chars, self.max_input_chars
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Middleware rejecting requests with 503 once `max_in_flight` requests are
+/// already being processed, instead of queueing them without bound, and
+/// once the server is shutting down.
+pub async fn limit_in_flight(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match acquire_request(&state) {
+        Ok(_permit) => next.run(request).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Take an in-flight slot, for handlers whose work outlives the handler
+/// itself, such as streamed responses.
+pub fn acquire_request(state: &AppState) -> Result<OwnedSemaphorePermit> {
+    if state.is_shutting_down() {
+        return Err(Error::Overloaded("Server is shutting down".to_string()));
+    }
+
+    state.try_acquire_request().ok_or_else(|| {
+        Error::Overloaded(format!(
+            "{} requests already in flight",
+            state.options().limits.max_in_flight
+        ))
+    })
+}
@@ -0,0 +1,70 @@
+use super::auth::KeyLabel;
+use super::AppState;
+use crate::metrics;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Per-request slot a handler fills in with the model it served, so the
+/// metrics layer can label requests whose model only appears in the body.
+#[derive(Clone, Default)]
+pub struct ModelLabel(Arc<Mutex<Option<String>>>);
+
+impl ModelLabel {
+    pub fn set(&self, model: &str) {
+        *self.0.lock().unwrap() = Some(model.to_string());
+    }
+
+    fn get(&self) -> String {
+        self.0.lock().unwrap().clone().unwrap_or_default()
+    }
+}
+
+/// Middleware recording request counts and latencies per route, model and
+/// status, and request counts per API key label.
+pub async fn track_metrics(mut request: Request, next: Next) -> Response {
+    let started = Instant::now();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+
+    let label = ModelLabel::default();
+    request.extensions_mut().insert(label.clone());
+
+    let response = next.run(request).await;
+
+    let status = response.status().as_u16().to_string();
+    let model = label.get();
+    let key = response
+        .extensions()
+        .get::<KeyLabel>()
+        .map(|label| label.0.as_str())
+        .unwrap_or_default();
+    let labels = [route.as_str(), model.as_str(), status.as_str()];
+
+    metrics::HTTP_REQUESTS
+        .with_label_values(&[route.as_str(), model.as_str(), status.as_str(), key])
+        .inc();
+    metrics::HTTP_REQUEST_DURATION
+        .with_label_values(&labels)
+        .observe(started.elapsed().as_secs_f64());
+
+    response
+}
+
+pub async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    metrics::LOADED_MODELS.set(state.loaded_models().await.len() as i64);
+    metrics::LOADED_MEMORY_BYTES.set(state.memory_bytes().await as i64);
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics::render(),
+    )
+}
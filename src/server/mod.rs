@@ -1,160 +1,512 @@
-use crate::config::Config;
-use crate::embedder::Embedder;
+mod auth;
+mod batcher;
+mod codec;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod limits;
+mod metrics;
+mod policy;
+mod state;
+mod stream;
+#[cfg(test)]
+mod testing;
+mod tls;
+mod trace;
+mod ws;
+
+pub use state::{AppState, KeepAlive, ModelStatus, ServerOptions};
+
+use crate::embedder::encoding::{EncodedEmbeddings, Encoding, EncodingFormat};
+use crate::embedder::EmbedOptions;
 use crate::error::{Error, Result};
-use crate::model::ModelRegistry;
+use auth::ApiKey;
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{DefaultBodyLimit, Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
     response::{IntoResponse, Response},
-    routing::{get, post},
-    Json, Router,
+    routing::{delete, get, post},
+    Extension, Json, Router,
 };
-use candle_core::Device;
+use codec::{Payload, ResponseFormat};
+use metrics::ModelLabel;
+use policy::TokenUsage;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
-
-#[derive(Clone)]
-pub struct AppState {
-    embedders: Arc<RwLock<HashMap<String, Embedder>>>,
-    config: Config,
-    device: Device,
-}
-
-impl AppState {
-    pub fn new(config: Config, device: Device) -> Self {
-        Self {
-            embedders: Arc::new(RwLock::new(HashMap::new())),
-            config,
-            device,
-        }
-    }
-
-    pub async fn get_or_load_embedder(&self, model_name: &str) -> Result<()> {
-        let embedders = self.embedders.read().await;
-        if embedders.contains_key(model_name) {
-            return Ok(());
-        }
-        drop(embedders);
-
-        // Load the model
-        let registry = ModelRegistry::load(&self.config)?;
-        let model_info = registry.get_model(model_name)?;
-
-        tracing::info!(
-            "Loading model '{}' on device '{:?}'",
-            model_name,
-            self.device
-        );
-        let embedder = Embedder::load(model_info, self.device.clone())?;
-
-        let mut embedders = self.embedders.write().await;
-        embedders.insert(model_name.to_string(), embedder);
-
-        Ok(())
-    }
-}
+use state::EvictionStats;
+use std::time::Duration;
+use tower::ServiceBuilder;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tower_http::LatencyUnit;
+use tracing::Level;
 
 #[derive(Serialize)]
 pub struct HealthResponse {
     pub status: String,
+    pub version: String,
+    pub uptime_seconds: u64,
     pub loaded_models: Vec<String>,
+    pub models: Vec<ModelStatus>,
     pub device: String,
+    pub memory_bytes: u64,
+    pub memory_budget: Option<u64>,
+    pub max_loaded_models: Option<usize>,
+    pub evictions: EvictionStats,
+    /// Requests waiting in batching queues across all models.
+    pub queue_depth: usize,
+}
+
+#[derive(Serialize)]
+pub struct ProbeResponse {
+    pub status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct EmbedRequest {
     pub model: String,
     pub input: Vec<String>,
+    /// Seconds to keep the model loaded after this request (negative = forever)
+    #[serde(default)]
+    pub keep_alive: Option<i64>,
+    /// Input type, output dimensions, and overrides of the model's
+    /// configured pooling, normalization and maximum sequence length
+    #[serde(flatten)]
+    pub options: EmbedOptions,
+    /// `encoding_format` and `int8_range`
+    #[serde(flatten)]
+    pub encoding: Encoding,
 }
 
 #[derive(Serialize)]
 pub struct EmbedResponse {
     pub model: String,
     pub dimension: usize,
-    pub embeddings: Vec<Vec<f32>>,
+    pub embeddings: EncodedEmbeddings,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub int8_range: Option<[f32; 2]>,
+}
+
+/// Status code and client-facing message for an error. Internal errors are
+/// not described to clients.
+fn error_status(error: &Error) -> (StatusCode, String) {
+    match error {
+        Error::ModelNotFound(_) => (StatusCode::NOT_FOUND, error.to_string()),
+        Error::InvalidInput(_) => (StatusCode::BAD_REQUEST, error.to_string()),
+        Error::PayloadTooLarge(_) => (StatusCode::PAYLOAD_TOO_LARGE, error.to_string()),
+        Error::Overloaded(_) => (StatusCode::SERVICE_UNAVAILABLE, error.to_string()),
+        Error::Unauthorized(_) => (StatusCode::UNAUTHORIZED, error.to_string()),
+        Error::Forbidden(_) => (StatusCode::FORBIDDEN, error.to_string()),
+        Error::RateLimited(_) => (StatusCode::TOO_MANY_REQUESTS, error.to_string()),
+        Error::ModelLoadFailed(_) | Error::Embedding(_) => {
+            (StatusCode::INTERNAL_SERVER_ERROR, error.to_string())
+        }
+        _ => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal server error".to_string(),
+        ),
+    }
+}
+
+/// Message for an error reported inside a response body, such as a line of
+/// a streamed response.
+fn public_error(error: &Error) -> String {
+    let (status, message) = error_status(error);
+    if status == StatusCode::INTERNAL_SERVER_ERROR {
+        tracing::error!("Request failed: {}", error);
+    }
+    message
 }
 
 impl IntoResponse for Error {
     fn into_response(self) -> Response {
-        let (status, message) = match self {
-            Error::ModelNotFound(_) => (StatusCode::NOT_FOUND, self.to_string()),
-            Error::InvalidInput(_) => (StatusCode::BAD_REQUEST, self.to_string()),
-            Error::ModelLoadFailed(_) | Error::Embedding(_) => {
-                (StatusCode::INTERNAL_SERVER_ERROR, self.to_string())
-            }
-            _ => (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Internal server error".to_string(),
-            ),
-        };
+        let (status, message) = error_status(&self);
 
         let body = Json(serde_json::json!({
             "error": message,
         }));
 
-        (status, body).into_response()
+        let mut response = (status, body).into_response();
+        response
+            .extensions_mut()
+            .insert(trace::ErrorMessage(message));
+        match self {
+            Error::Overloaded(_) => {
+                response.headers_mut().insert(
+                    header::RETRY_AFTER,
+                    HeaderValue::from(limits::RETRY_AFTER_SECS),
+                );
+            }
+            Error::Unauthorized(_) => {
+                response
+                    .headers_mut()
+                    .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+/// Overall health: unavailable when not ready, degraded when a model has
+/// recently failed.
+async fn health_status(state: &AppState, models: &[ModelStatus]) -> &'static str {
+    if state.readiness().await.is_err() {
+        "unavailable"
+    } else if models.iter().any(|m| m.last_error.is_some()) {
+        "degraded"
+    } else {
+        "ok"
     }
 }
 
 async fn health_handler(State(state): State<AppState>) -> Result<Json<HealthResponse>> {
-    let embedders = state.embedders.read().await;
-    let loaded_models: Vec<String> = embedders.keys().cloned().collect();
+    let models = state.list_models().await?;
+    let status = health_status(&state, &models).await;
+
+    let queue_depth = models.iter().map(|m| m.queue_depth).sum();
 
     Ok(Json(HealthResponse {
-        status: "ok".to_string(),
-        loaded_models,
-        device: format!("{:?}", state.device),
+        status: status.to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_seconds: state.uptime().as_secs(),
+        loaded_models: state.loaded_models().await,
+        models,
+        device: format!("{:?}", state.device()),
+        memory_bytes: state.memory_bytes().await,
+        memory_budget: state.options().memory_budget,
+        max_loaded_models: state.options().max_loaded_models,
+        evictions: state.eviction_stats(),
+        queue_depth,
     }))
 }
 
+async fn livez_handler() -> Json<ProbeResponse> {
+    Json(ProbeResponse {
+        status: "ok".to_string(),
+        reason: None,
+    })
+}
+
+async fn readyz_handler(State(state): State<AppState>) -> (StatusCode, Json<ProbeResponse>) {
+    match state.readiness().await {
+        Ok(()) => (
+            StatusCode::OK,
+            Json(ProbeResponse {
+                status: "ready".to_string(),
+                reason: None,
+            }),
+        ),
+        Err(reason) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(ProbeResponse {
+                status: "not_ready".to_string(),
+                reason: Some(reason),
+            }),
+        ),
+    }
+}
+
 async fn embed_handler(
     State(state): State<AppState>,
-    Json(payload): Json<EmbedRequest>,
-) -> Result<Json<EmbedResponse>> {
-    if payload.input.is_empty() {
-        return Err(Error::InvalidInput("Input cannot be empty".to_string()));
+    Extension(label): Extension<ModelLabel>,
+    headers: HeaderMap,
+    Payload(payload): Payload<EmbedRequest>,
+) -> Result<(Extension<TokenUsage>, Response)> {
+    let span = tracing::Span::current();
+    span.record("model", payload.model.as_str());
+    span.record("inputs", payload.input.len());
+    state.options().limits.check_inputs(&payload.input)?;
+    payload.encoding.check()?;
+
+    let format = ResponseFormat::from_headers(&headers);
+    if format == ResponseFormat::F32Matrix
+        && payload.encoding.encoding_format != EncodingFormat::Float
+    {
+        return Err(Error::InvalidInput(format!(
+            "{} responses require encoding_format \"float\"",
+            codec::F32_MATRIX
+        )));
     }
 
-    // Load model if not already loaded
-    state.get_or_load_embedder(&payload.model).await?;
+    let keep_alive = payload.keep_alive.map(KeepAlive::from_secs);
+    let result = state
+        .embed(&payload.model, payload.input, payload.options, keep_alive)
+        .await?;
+    // Only label known models so clients can't inflate metric cardinality
+    label.set(&payload.model);
 
-    let embedders = state.embedders.read().await;
-    let embedder = embedders
-        .get(&payload.model)
-        .ok_or_else(|| Error::ModelNotFound(payload.model.clone()))?;
+    let response = if format == ResponseFormat::F32Matrix {
+        codec::f32_matrix(&result.embeddings, result.dimension)
+    } else {
+        let encoded = payload
+            .encoding
+            .apply(result.embeddings, result.int8_range)?;
+        format.encode(&EmbedResponse {
+            model: payload.model,
+            dimension: result.dimension,
+            embeddings: encoded.embeddings,
+            int8_range: encoded.int8_range,
+        })?
+    };
 
-    let embeddings = embedder.embed(&payload.input)?;
-    let dimension = embedder.embedding_dim();
+    Ok((Extension(TokenUsage(result.tokens)), response))
+}
 
-    Ok(Json(EmbedResponse {
-        model: payload.model,
-        dimension,
-        embeddings,
-    }))
+#[derive(Serialize)]
+pub struct ModelsResponse {
+    pub models: Vec<ModelStatus>,
 }
 
-pub fn create_router(state: AppState) -> Router {
-    Router::new()
-        .route("/api/health", get(health_handler))
+async fn list_models_handler(
+    State(state): State<AppState>,
+    key: Option<Extension<ApiKey>>,
+) -> Result<Json<ModelsResponse>> {
+    let mut models = state.list_models().await?;
+    // Keys only see the models they may use
+    if let Some(Extension(key)) = key {
+        models.retain(|model| key.policy.allows(&model.name));
+    }
+    Ok(Json(ModelsResponse { models }))
+}
+
+async fn load_model_handler(
+    State(state): State<AppState>,
+    Extension(label): Extension<ModelLabel>,
+    Path(name): Path<String>,
+) -> Result<Json<ModelStatus>> {
+    state.get_or_load_embedder(&name, None).await?;
+    label.set(&name);
+    Ok(Json(state.model_status(&name).await?))
+}
+
+async fn unload_model_handler(
+    State(state): State<AppState>,
+    Extension(label): Extension<ModelLabel>,
+    key: Option<Extension<ApiKey>>,
+    Path(name): Path<String>,
+) -> Result<Json<ModelStatus>> {
+    if let Some(Extension(key)) = key {
+        if !key.policy.may_unload() {
+            return Err(Error::Forbidden(format!(
+                "API key '{}' may not unload models",
+                key.label
+            )));
+        }
+    }
+    state.unload_model(&name).await?;
+    label.set(&name);
+    Ok(Json(state.model_status(&name).await?))
+}
+
+pub fn create_router(state: AppState) -> Result<Router> {
+    // Inference routes are subject to the in-flight limit; probes, health
+    // and metrics must keep answering while the server is saturated.
+    let inference = Router::new()
         .route("/api/embed", post(embed_handler))
-        .with_state(state)
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            limits::limit_in_flight,
+        ));
+
+    // Everything but the health probes requires an API key, when configured
+    let protected = Router::new()
+        .merge(inference)
+        // Holds its in-flight slot for as long as the response streams
+        .route("/api/embed/stream", post(stream::embed_stream_handler))
+        // Admits each frame as its own request
+        .route("/api/ws", get(ws::ws_handler))
+        .route("/api/models", get(list_models_handler))
+        .route("/api/models/{name}", delete(unload_model_handler))
+        .route("/api/models/{name}/load", post(load_model_handler))
+        .route("/metrics", get(metrics::metrics_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            policy::enforce_key_policy,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_api_key,
+        ));
+
+    let mut router = Router::new()
+        .route("/livez", get(livez_handler))
+        .route("/readyz", get(readyz_handler))
+        .route("/api/health", get(health_handler))
+        .merge(protected)
+        .route_layer(middleware::from_fn(metrics::track_metrics))
+        .layer(DefaultBodyLimit::max(
+            state.options().limits.max_request_bytes,
+        ))
+        .layer(middleware::from_fn(trace::request_id_in_errors));
+
+    if let Some(cors) = trace::cors_layer(&state.options().cors_origins)? {
+        router = router.layer(cors);
+    }
+
+    // Outermost: assign the request ID first so every layer below can see it
+    let router = router.layer(
+        ServiceBuilder::new()
+            .layer(SetRequestIdLayer::new(
+                trace::REQUEST_ID_HEADER,
+                MakeRequestUuid,
+            ))
+            .layer(PropagateRequestIdLayer::new(trace::REQUEST_ID_HEADER))
+            .layer(
+                TraceLayer::new_for_http()
+                    .make_span_with(trace::make_span)
+                    .on_request(())
+                    .on_response(
+                        DefaultOnResponse::new()
+                            .level(Level::INFO)
+                            .latency_unit(LatencyUnit::Millis),
+                    ),
+            ),
+    );
+
+    Ok(router.with_state(state))
+}
+
+/// Resolves on SIGINT (Ctrl+C) or, on Unix, SIGTERM.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            tracing::error!("Failed to listen for Ctrl+C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(e) => {
+                tracing::error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
 }
 
+/// How long rejected requests get to deliver their error responses once the
+/// drain timeout has passed.
+const REJECT_GRACE: Duration = Duration::from_secs(1);
+
 pub async fn serve(host: &str, port: u16, state: AppState) -> Result<()> {
-    let app = create_router(state);
-    let addr = format!("{}:{}", host, port);
+    // Check the certificate before spending time on preloads
+    let tls = match &state.options().tls {
+        Some(options) => Some((options.clone(), options.rustls_config()?)),
+        None => None,
+    };
+
+    let signal_state = state.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        signal_state.begin_shutdown();
+    });
+
+    tokio::select! {
+        result = state.preload() => result?,
+        _ = state.shutdown_requested() => return Ok(()),
+    }
 
-    tracing::info!("Starting server on {}", addr);
+    let app = create_router(state.clone())?;
+    state.spawn_reaper();
+    let addr = format!("{}:{}", host, port);
 
     let listener = tokio::net::TcpListener::bind(&addr)
         .await
         .map_err(|e| Error::Config(format!("Failed to bind to {}: {}", addr, e)))?;
 
-    axum::serve(listener, app)
-        .await
-        .map_err(|e| Error::Config(format!("Server error: {}", e)))?;
+    #[cfg(feature = "grpc")]
+    let grpc = match state.options().grpc_port {
+        Some(grpc_port) => Some(grpc::bind(host, grpc_port, state.clone()).await?),
+        None => None,
+    };
+
+    let server_state = state.clone();
+    let server = async move {
+        match tls {
+            Some((options, config)) => {
+                tracing::info!("Starting server on {} (TLS)", addr);
+                options.spawn_reloader(config.clone());
+
+                let handle = axum_server::Handle::new();
+                let shutdown_handle = handle.clone();
+                tokio::spawn(async move {
+                    server_state.shutdown_requested().await;
+                    shutdown_handle.graceful_shutdown(None);
+                });
+
+                axum_server::from_tcp_rustls(listener.into_std()?, config)
+                    .handle(handle)
+                    .serve(app.into_make_service())
+                    .await
+            }
+            None => {
+                tracing::info!("Starting server on {}", addr);
+                axum::serve(listener, app)
+                    .with_graceful_shutdown(async move { server_state.shutdown_requested().await })
+                    .await
+            }
+        }
+        .map_err(|e| Error::Config(format!("Server error: {}", e)))
+    };
+
+    // WebSocket sessions are detached from their HTTP connection, so wait
+    // for them separately
+    let session_state = state.clone();
+    let server = async move {
+        server.await?;
+        session_state.sessions_closed().await;
+        Ok::<_, Error>(())
+    };
+
+    #[cfg(feature = "grpc")]
+    let server = async move {
+        match grpc {
+            Some(grpc) => tokio::try_join!(server, grpc).map(|_| ()),
+            None => server.await,
+        }
+    };
+
+    let drain_timeout = state.options().drain_timeout;
+    let drain_deadline = async {
+        state.shutdown_requested().await;
+        tokio::time::sleep(drain_timeout).await;
+    };
+
+    tokio::select! {
+        result = server => {
+            result?;
+            tracing::info!("All requests drained, server stopped");
+        }
+        _ = drain_deadline => {
+            tracing::warn!(
+                "Drain timeout of {:?} reached with {} requests in flight, rejecting queued work",
+                drain_timeout,
+                state.in_flight_requests()
+            );
+            state.reject_queued().await;
+
+            let grace = tokio::time::Instant::now() + REJECT_GRACE;
+            while state.in_flight_requests() > 0 && tokio::time::Instant::now() < grace {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,279 @@
+use super::auth::ApiKey;
+use super::AppState;
+use crate::error::{Error, Result};
+use axum::{
+    body::{Body, Bytes},
+    extract::{
+        rejection::BytesRejection, FromRequest, FromRequestParts, MatchedPath, Query,
+        RawPathParams, Request, State,
+    },
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Per-key limits, read from the `[[keys]]` entries of the keys file.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct KeyPolicy {
+    /// Sustained request rate; bursts of up to one second's worth are allowed.
+    #[serde(default)]
+    pub requests_per_second: Option<f64>,
+    #[serde(default)]
+    pub tokens_per_minute: Option<u64>,
+    /// Models (names or aliases, as used in requests) this key may use; all
+    /// models when unset.
+    #[serde(default)]
+    pub models: Option<Vec<String>>,
+    /// May unload models other keys share, even with a `models` allow-list.
+    #[serde(default)]
+    pub admin: bool,
+    #[serde(skip)]
+    limiter: Arc<Limiter>,
+}
+
+impl KeyPolicy {
+    /// Validate the configured limits and set up fresh token buckets.
+    pub fn init(&mut self) -> std::result::Result<(), String> {
+        if let Some(rps) = self.requests_per_second {
+            if !(rps > 0.0 && rps.is_finite()) {
+                return Err(format!("requests_per_second must be positive, got {}", rps));
+            }
+        }
+        if self.tokens_per_minute == Some(0) {
+            return Err("tokens_per_minute must be positive".to_string());
+        }
+
+        self.limiter = Arc::new(Limiter {
+            requests: self
+                .requests_per_second
+                .map(|rps| Mutex::new(TokenBucket::new(rps.max(1.0), rps))),
+            tokens: self.tokens_per_minute.map(|tpm| {
+                let tpm = tpm as f64;
+                Mutex::new(TokenBucket::new(tpm, tpm / 60.0))
+            }),
+        });
+        Ok(())
+    }
+
+    pub fn allows(&self, model: &str) -> bool {
+        self.models
+            .as_ref()
+            .is_none_or(|models| models.iter().any(|m| m == model))
+    }
+
+    /// Whether the key may unload models: admin keys and keys without a
+    /// model allow-list.
+    pub fn may_unload(&self) -> bool {
+        self.admin || self.models.is_none()
+    }
+
+    /// Check the allow-list for `model`, if known, then admit the request
+    /// against the rate limits. A rate-limited request also gets how long
+    /// the client should wait.
+    pub fn admit(
+        &self,
+        label: &str,
+        model: Option<&str>,
+    ) -> std::result::Result<(), (Error, Option<Duration>)> {
+        if let Some(model) = model.filter(|m| !self.allows(m)) {
+            return Err((
+                Error::Forbidden(format!("API key '{}' may not use model '{}'", label, model)),
+                None,
+            ));
+        }
+
+        self.limiter.admit().map_err(|(limit, wait)| {
+            (
+                Error::RateLimited(format!("API key '{}' exceeded its {} limit", label, limit)),
+                Some(wait),
+            )
+        })
+    }
+
+    /// Charge the tokens a request processed to the key's token budget.
+    pub fn charge_tokens(&self, tokens: usize) {
+        self.limiter.charge_tokens(tokens);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Limiter {
+    requests: Option<Mutex<TokenBucket>>,
+    tokens: Option<Mutex<TokenBucket>>,
+}
+
+impl Limiter {
+    /// Admit a request, or return how long the client should wait.
+    ///
+    /// Token usage is only known once a request has been tokenized, so it is
+    /// charged afterwards and may push the bucket into debt; new requests are
+    /// admitted again once the debt has been paid off.
+    fn admit(&self) -> std::result::Result<(), (&'static str, Duration)> {
+        if let Some(tokens) = &self.tokens {
+            let wait = tokens.lock().unwrap().wait_for(0.0);
+            if !wait.is_zero() {
+                return Err(("tokens per minute", wait));
+            }
+        }
+
+        if let Some(requests) = &self.requests {
+            let mut requests = requests.lock().unwrap();
+            let wait = requests.wait_for(1.0);
+            if !wait.is_zero() {
+                return Err(("requests per second", wait));
+            }
+            requests.take(1.0);
+        }
+
+        Ok(())
+    }
+
+    fn charge_tokens(&self, tokens: usize) {
+        if let Some(bucket) = &self.tokens {
+            bucket.lock().unwrap().take(tokens as f64);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    per_second: f64,
+    available: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(capacity: f64, per_second: f64) -> Self {
+        Self {
+            capacity,
+            per_second,
+            available: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.available = (self.available + elapsed * self.per_second).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Time until `amount` is available, zero if it already is.
+    fn wait_for(&mut self, amount: f64) -> Duration {
+        self.refill();
+        if self.available >= amount {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((amount - self.available) / self.per_second)
+        }
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.refill();
+        self.available -= amount;
+    }
+}
+
+/// Tokens processed for a request, attached to the response by handlers so
+/// the policy layer can charge them to the key.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenUsage(pub usize);
+
+#[derive(Deserialize)]
+struct ModelField {
+    model: Option<String>,
+}
+
+/// Middleware enforcing the authenticated key's model allow-list and rate
+/// limits before the request reaches a handler (and so before any model is
+/// loaded).
+pub async fn enforce_key_policy(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(key) = request.extensions().get::<ApiKey>().cloned() else {
+        return next.run(request).await;
+    };
+    let policy = &key.policy;
+
+    let (request, models) = if policy.models.is_some() {
+        match requested_models(&state, request).await {
+            Ok(result) => result,
+            Err(e) => return e.into_response(),
+        }
+    } else {
+        (request, Vec::new())
+    };
+
+    let denied = models.iter().find(|model| !policy.allows(model));
+    if let Err((error, wait)) = policy.admit(&key.label, denied.map(String::as_str)) {
+        let mut response = error.into_response();
+        if let Some(wait) = wait {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                HeaderValue::from(wait.as_secs_f64().ceil().max(1.0) as u64),
+            );
+        }
+        return response;
+    }
+
+    let response = next.run(request).await;
+    if let Some(TokenUsage(tokens)) = response.extensions().get::<TokenUsage>() {
+        policy.charge_tokens(*tokens);
+    }
+    response
+}
+
+/// Routes whose request body names the model. Other routes are never
+/// buffered, so streaming bodies reach their handler untouched.
+const BODY_MODEL_ROUTES: &[&str] = &["/api/embed"];
+
+/// Find the models a request targets, from the `{name}` path parameter, the
+/// `model` query parameter, and the `model` field of a JSON or MessagePack
+/// body on routes that take one. The body is buffered and put back.
+async fn requested_models(state: &AppState, request: Request) -> Result<(Request, Vec<String>)> {
+    let (mut parts, body) = request.into_parts();
+
+    if let Ok(params) = RawPathParams::from_request_parts(&mut parts, state).await {
+        if let Some((_, name)) = params.iter().find(|(param, _)| *param == "name") {
+            let name = name.to_string();
+            return Ok((Request::from_parts(parts, body), vec![name]));
+        }
+    }
+
+    let mut models: Vec<String> = Query::<ModelField>::try_from_uri(&parts.uri)
+        .ok()
+        .and_then(|Query(field)| field.model)
+        .into_iter()
+        .collect();
+
+    let body_names_model = parts.method == Method::POST
+        && parts
+            .extensions
+            .get::<MatchedPath>()
+            .is_some_and(|path| BODY_MODEL_ROUTES.contains(&path.as_str()));
+    if !body_names_model {
+        return Ok((Request::from_parts(parts, body), models));
+    }
+
+    // Buffered under the same body limit as the handler's extractor
+    let bytes = Bytes::from_request(Request::from_parts(parts.clone(), body), state)
+        .await
+        .map_err(|rejection: BytesRejection| match rejection.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => Error::PayloadTooLarge(rejection.body_text()),
+            _ => Error::InvalidInput(rejection.body_text()),
+        })?;
+    models.extend(
+        super::codec::decode::<ModelField>(&parts.headers, &bytes)
+            .ok()
+            .and_then(|field| field.model),
+    );
+
+    Ok((Request::from_parts(parts, Body::from(bytes)), models))
+}
@@ -0,0 +1,1038 @@
+use super::auth::ApiKeys;
+use super::batcher::{BatchOptions, Batcher};
+use super::limits::RequestLimits;
+use super::tls::TlsOptions;
+use crate::config::{Config, Settings};
+use crate::embedder::{EmbedOptions, Embedder};
+use crate::error::{Error, Result};
+use crate::metrics;
+use crate::model::convert::{PYTORCH_FILE, SAFETENSORS_FILE};
+use crate::model::{ModelOptions, ModelRegistry};
+use candle_core::Device;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{watch, OwnedSemaphorePermit, RwLock, Semaphore};
+
+const MAX_RECENT_EVICTIONS: usize = 16;
+const REAPER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long an idle model stays loaded after its last use.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeepAlive {
+    #[default]
+    Forever,
+    For(Duration),
+}
+
+impl KeepAlive {
+    /// Negative values keep the model loaded indefinitely.
+    pub fn from_secs(secs: i64) -> Self {
+        if secs < 0 {
+            KeepAlive::Forever
+        } else {
+            KeepAlive::For(Duration::from_secs(secs as u64))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub workers: usize,
+    pub max_loaded_models: Option<usize>,
+    pub memory_budget: Option<u64>,
+    pub keep_alive: KeepAlive,
+    /// Models loaded and warmed up before the server starts listening.
+    pub preload: Vec<String>,
+    /// Start even if some preloads fail.
+    pub allow_partial: bool,
+    pub batch: BatchOptions,
+    pub limits: RequestLimits,
+    /// Accepted bearer tokens; empty disables authentication.
+    pub api_keys: ApiKeys,
+    /// Serve HTTPS instead of plain HTTP.
+    pub tls: Option<TlsOptions>,
+    /// How long in-flight requests may take to finish after a shutdown
+    /// signal before queued work is rejected and the server exits.
+    pub drain_timeout: Duration,
+    /// Origins allowed to call the API from a browser; `*` allows any.
+    pub cors_origins: Vec<String>,
+    /// Also serve the gRPC API on this port.
+    pub grpc_port: Option<u16>,
+    /// Options for models that don't set their own.
+    pub model_defaults: ModelOptions,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            workers: std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            max_loaded_models: None,
+            memory_budget: None,
+            keep_alive: KeepAlive::Forever,
+            preload: Vec::new(),
+            allow_partial: false,
+            batch: BatchOptions::default(),
+            limits: RequestLimits::default(),
+            api_keys: ApiKeys::default(),
+            tls: None,
+            drain_timeout: Duration::from_secs(30),
+            cors_origins: Vec::new(),
+            grpc_port: None,
+            model_defaults: ModelOptions::default(),
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Options from merged settings, with built-in defaults for anything
+    /// left unset.
+    pub fn from_settings(settings: &Settings) -> Result<Self> {
+        let defaults = Self::default();
+
+        let mut api_keys = ApiKeys::new(settings.auth.api_keys.clone().unwrap_or_default());
+        if let Some(path) = &settings.auth.api_keys_file {
+            api_keys = api_keys.load_file(path)?;
+        }
+
+        let tls = match (&settings.tls.cert, &settings.tls.key) {
+            (Some(cert), Some(key)) => Some(TlsOptions {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: settings.tls.client_ca.clone(),
+            }),
+            (None, None) if settings.tls.client_ca.is_none() => None,
+            _ => {
+                return Err(Error::Config(
+                    "TLS requires both a certificate and a key".to_string(),
+                ))
+            }
+        };
+
+        if settings.server.grpc_port.is_some() {
+            if !cfg!(feature = "grpc") {
+                return Err(Error::Config(
+                    "gRPC support requires building with `--features grpc`".to_string(),
+                ));
+            }
+            // Don't let gRPC become a plaintext way around TLS and client
+            // certificates
+            if tls.is_some() {
+                return Err(Error::Config(
+                    "The gRPC port does not support TLS; terminate TLS in front of it".to_string(),
+                ));
+            }
+        }
+
+        let batching = &settings.batching;
+        let limits = &settings.limits;
+        let models = &settings.models;
+
+        Ok(Self {
+            workers: settings.server.workers.unwrap_or(defaults.workers),
+            max_loaded_models: models.max_loaded_models,
+            memory_budget: models.memory_budget,
+            keep_alive: models
+                .keep_alive
+                .map(KeepAlive::from_secs)
+                .unwrap_or(defaults.keep_alive),
+            preload: models.preload.clone().unwrap_or_default(),
+            allow_partial: models.allow_partial.unwrap_or(defaults.allow_partial),
+            batch: BatchOptions {
+                max_batch_tokens: batching
+                    .max_batch_tokens
+                    .unwrap_or(defaults.batch.max_batch_tokens),
+                max_batch_requests: batching
+                    .max_batch_requests
+                    .unwrap_or(defaults.batch.max_batch_requests)
+                    .max(1),
+                batch_timeout: batching
+                    .batch_timeout_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(defaults.batch.batch_timeout),
+            },
+            limits: RequestLimits {
+                max_inputs_per_request: limits
+                    .max_inputs_per_request
+                    .unwrap_or(defaults.limits.max_inputs_per_request),
+                max_input_chars: limits
+                    .max_input_chars
+                    .unwrap_or(defaults.limits.max_input_chars),
+                max_request_bytes: limits
+                    .max_request_bytes
+                    .unwrap_or(defaults.limits.max_request_bytes),
+                max_in_flight: limits
+                    .max_in_flight
+                    .unwrap_or(defaults.limits.max_in_flight),
+            },
+            api_keys,
+            tls,
+            drain_timeout: settings
+                .server
+                .drain_timeout
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.drain_timeout),
+            cors_origins: settings.server.cors_origins.clone().unwrap_or_default(),
+            grpc_port: settings.server.grpc_port,
+            model_defaults: ModelOptions {
+                pooling: settings.model_defaults.pooling,
+                normalize: settings.model_defaults.normalize,
+                max_seq_length: settings.model_defaults.max_seq_length,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+type LoadResult = Option<Result<Arc<LoadedModel>>>;
+
+/// A model entry in [`AppState`]: either fully loaded, or being loaded by a
+/// background task whose result every concurrent caller waits on.
+enum ModelSlot {
+    Loading {
+        rx: watch::Receiver<LoadResult>,
+        /// Estimated memory held for the model while it loads
+        reserved: u64,
+    },
+    Loaded(Arc<LoadedModel>),
+}
+
+impl ModelSlot {
+    fn memory_bytes(&self) -> u64 {
+        match self {
+            ModelSlot::Loading { reserved, .. } => *reserved,
+            ModelSlot::Loaded(model) => model.embedder.memory_bytes(),
+        }
+    }
+}
+
+struct LoadedModel {
+    embedder: Arc<Embedder>,
+    batcher: Batcher,
+    loaded_at: String,
+    load_duration: Duration,
+    usage: Mutex<Usage>,
+}
+
+struct Usage {
+    last_used: Instant,
+    keep_alive: KeepAlive,
+}
+
+impl LoadedModel {
+    fn touch(&self, keep_alive: Option<KeepAlive>) {
+        let mut usage = self.usage.lock().unwrap();
+        usage.last_used = Instant::now();
+        if let Some(keep_alive) = keep_alive {
+            usage.keep_alive = keep_alive;
+        }
+    }
+
+    fn last_used(&self) -> Instant {
+        self.usage.lock().unwrap().last_used
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        let usage = self.usage.lock().unwrap();
+        match usage.keep_alive {
+            KeepAlive::Forever => false,
+            KeepAlive::For(ttl) => now.duration_since(usage.last_used) >= ttl,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Eviction {
+    pub model: String,
+    pub reason: String,
+    pub evicted_at: String,
+}
+
+#[derive(Debug, Default)]
+struct Evictions {
+    total: u64,
+    recent: VecDeque<Eviction>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EvictionStats {
+    pub total: u64,
+    pub recent: Vec<Eviction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModelState {
+    Loaded,
+    Loading,
+    Unloaded,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelStatus {
+    pub name: String,
+    pub repository: String,
+    pub state: ModelState,
+    pub device: Option<String>,
+    pub loaded_at: Option<String>,
+    pub load_duration_ms: Option<u64>,
+    /// Estimated from the weights file size, whether or not the model is loaded.
+    pub memory_bytes: u64,
+    /// Most recent load or inference failure, cleared by the next success.
+    pub last_error: Option<String>,
+    /// Requests waiting in the model's batching queue.
+    pub queue_depth: usize,
+}
+
+pub struct Embeddings {
+    pub dimension: usize,
+    pub embeddings: Vec<Vec<f32>>,
+    pub tokens: usize,
+    /// The model's calibrated int8 range, for encoding
+    pub int8_range: Option<[f32; 2]>,
+}
+
+pub struct SessionGuard(Arc<watch::Sender<usize>>);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|sessions| *sessions -= 1);
+    }
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    embedders: Arc<RwLock<HashMap<String, ModelSlot>>>,
+    config: Config,
+    device: Device,
+    options: ServerOptions,
+    workers: Arc<Semaphore>,
+    in_flight: Arc<Semaphore>,
+    evictions: Arc<Mutex<Evictions>>,
+    errors: Arc<Mutex<HashMap<String, String>>>,
+    started_at: Instant,
+    startup_complete: Arc<AtomicBool>,
+    shutdown: Arc<watch::Sender<bool>>,
+    /// Open WebSocket sessions, which outlive the HTTP server's own
+    /// graceful shutdown
+    sessions: Arc<watch::Sender<usize>>,
+    /// Preloads that loaded successfully. They are pinned: never evicted for
+    /// being idle or to make room, only unloaded explicitly.
+    preloaded: Arc<Mutex<HashSet<String>>>,
+}
+
+impl AppState {
+    pub fn new(config: Config, device: Device, options: ServerOptions) -> Self {
+        Self {
+            embedders: Arc::new(RwLock::new(HashMap::new())),
+            config,
+            device,
+            workers: Arc::new(Semaphore::new(options.workers.max(1))),
+            in_flight: Arc::new(Semaphore::new(options.limits.max_in_flight.max(1))),
+            options,
+            evictions: Arc::new(Mutex::new(Evictions::default())),
+            errors: Arc::new(Mutex::new(HashMap::new())),
+            started_at: Instant::now(),
+            startup_complete: Arc::new(AtomicBool::new(false)),
+            shutdown: Arc::new(watch::channel(false).0),
+            sessions: Arc::new(watch::channel(0).0),
+            preloaded: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    pub fn options(&self) -> &ServerOptions {
+        &self.options
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Start shutting down: readiness fails from now on and new inference
+    /// requests are turned away, while in-flight ones are left to finish.
+    pub fn begin_shutdown(&self) {
+        if !self.shutdown.send_replace(true) {
+            tracing::info!("Shutting down, draining in-flight requests");
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        *self.shutdown.borrow()
+    }
+
+    /// Resolves once [`AppState::begin_shutdown`] has been called.
+    pub async fn shutdown_requested(&self) {
+        let mut shutdown = self.shutdown.subscribe();
+        let _ = shutdown.wait_for(|&shutdown| shutdown).await;
+    }
+
+    /// Count a WebSocket session as open until the guard is dropped.
+    pub fn open_session(&self) -> SessionGuard {
+        self.sessions.send_modify(|sessions| *sessions += 1);
+        SessionGuard(self.sessions.clone())
+    }
+
+    /// Resolves once every WebSocket session has closed.
+    pub async fn sessions_closed(&self) {
+        let mut sessions = self.sessions.subscribe();
+        let _ = sessions.wait_for(|&sessions| sessions == 0).await;
+    }
+
+    /// Number of requests currently holding an in-flight slot.
+    pub fn in_flight_requests(&self) -> usize {
+        self.options.limits.max_in_flight.max(1) - self.in_flight.available_permits()
+    }
+
+    /// Fail every request still waiting in a batching queue, so clients get
+    /// an error instead of a dropped connection when the drain times out.
+    pub async fn reject_queued(&self) {
+        let embedders = self.embedders.read().await;
+        for slot in embedders.values() {
+            if let ModelSlot::Loaded(model) = slot {
+                model.batcher.close();
+            }
+        }
+    }
+
+    /// Reserve a slot for an incoming request, or `None` if the server is
+    /// already handling `limits.max_in_flight` requests.
+    pub fn try_acquire_request(&self) -> Option<OwnedSemaphorePermit> {
+        self.in_flight.clone().try_acquire_owned().ok()
+    }
+
+    /// Load and warm up every model in `options.preload`, then mark startup
+    /// as complete. Fails on the first error unless `allow_partial` is set.
+    pub async fn preload(&self) -> Result<()> {
+        for model in &self.options.preload {
+            tracing::info!("Preloading model '{}'", model);
+            match self.warm_up(model).await {
+                Ok(()) => {
+                    self.preloaded.lock().unwrap().insert(model.clone());
+                    tracing::info!("Model '{}' ready", model);
+                }
+                Err(e) if self.options.allow_partial => {
+                    tracing::warn!("Failed to preload model '{}': {}", model, e);
+                }
+                Err(e) => {
+                    tracing::error!("Failed to preload model '{}': {}", model, e);
+                    return Err(e);
+                }
+            }
+        }
+
+        self.startup_complete.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn is_pinned(&self, model_name: &str) -> bool {
+        self.preloaded.lock().unwrap().contains(model_name)
+    }
+
+    /// Ready once startup completed and every successfully preloaded model
+    /// is loaded and answers a canary inference.
+    pub async fn readiness(&self) -> std::result::Result<(), String> {
+        if self.is_shutting_down() {
+            return Err("shutting down".to_string());
+        }
+        if !self.startup_complete.load(Ordering::SeqCst) {
+            return Err("startup in progress".to_string());
+        }
+
+        // Preloads that failed under `allow_partial` don't hold readiness back
+        let preloaded: Vec<String> = self.preloaded.lock().unwrap().iter().cloned().collect();
+        let failed: Vec<String> = {
+            let embedders = self.embedders.read().await;
+            if let Some(model) = preloaded
+                .iter()
+                .find(|model| !matches!(embedders.get(*model), Some(ModelSlot::Loaded(_))))
+            {
+                return Err(format!("model '{}' is not loaded", model));
+            }
+            let errors = self.errors.lock().unwrap();
+            preloaded
+                .into_iter()
+                .filter(|model| errors.contains_key(model))
+                .collect()
+        };
+
+        // A failed request may have been transient or the request's own
+        // fault. Re-run the canary instead of trusting the recorded error:
+        // once unready, no traffic would arrive to clear it.
+        for model in failed {
+            self.warm_up(&model)
+                .await
+                .map_err(|e| format!("model '{}' failed: {}", model, e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Remember the most recent failure for a model, for health reporting.
+    pub fn record_error(&self, model_name: &str, error: &Error) {
+        self.errors
+            .lock()
+            .unwrap()
+            .insert(model_name.to_string(), error.to_string());
+    }
+
+    pub fn clear_error(&self, model_name: &str) {
+        self.errors.lock().unwrap().remove(model_name);
+    }
+
+    /// Return the embedder for `model_name`, loading it if needed.
+    ///
+    /// `keep_alive` overrides the model's idle TTL from this request on.
+    pub async fn get_or_load_embedder(
+        &self,
+        model_name: &str,
+        keep_alive: Option<KeepAlive>,
+    ) -> Result<Arc<Embedder>> {
+        let model = self.get_or_load_model(model_name, keep_alive).await?;
+        Ok(model.embedder.clone())
+    }
+
+    /// Embed `texts` with `model_name` through the model's batching queue,
+    /// loading the model if needed.
+    pub async fn embed(
+        &self,
+        model_name: &str,
+        texts: Vec<String>,
+        options: EmbedOptions,
+        keep_alive: Option<KeepAlive>,
+    ) -> Result<Embeddings> {
+        let model = self.get_or_load_model(model_name, keep_alive).await?;
+        model.embedder.check_options(&options)?;
+        // Tokenizing happens on an inference worker; an estimate is enough
+        // to size batches
+        let estimate = model.embedder.estimate_tokens(&texts, &options);
+        let dimension = options.dimensions.unwrap_or(model.embedder.embedding_dim());
+
+        match model.batcher.embed(texts, estimate, options).await {
+            Ok((embeddings, tokens)) => {
+                self.clear_error(model_name);
+                Ok(Embeddings {
+                    dimension,
+                    embeddings,
+                    tokens,
+                    int8_range: model.embedder.int8_range(),
+                })
+            }
+            Err(e) => {
+                if !matches!(e, Error::InvalidInput(_) | Error::Overloaded(_)) {
+                    self.record_error(model_name, &e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    async fn get_or_load_model(
+        &self,
+        model_name: &str,
+        keep_alive: Option<KeepAlive>,
+    ) -> Result<Arc<LoadedModel>> {
+        let embedders = self.embedders.read().await;
+        match embedders.get(model_name) {
+            Some(ModelSlot::Loaded(model)) => {
+                model.touch(keep_alive);
+                return Ok(model.clone());
+            }
+            Some(ModelSlot::Loading { rx, .. }) => {
+                let rx = rx.clone();
+                drop(embedders);
+                return Self::wait_for_load(rx).await;
+            }
+            None => {}
+        }
+        drop(embedders);
+
+        let mut embedders = self.embedders.write().await;
+        let rx = match embedders.get(model_name) {
+            Some(ModelSlot::Loaded(model)) => {
+                model.touch(keep_alive);
+                return Ok(model.clone());
+            }
+            Some(ModelSlot::Loading { rx, .. }) => rx.clone(),
+            None => {
+                let (tx, rx) = watch::channel(None);
+                embedders.insert(
+                    model_name.to_string(),
+                    ModelSlot::Loading {
+                        rx: rx.clone(),
+                        reserved: 0,
+                    },
+                );
+
+                // Load in a separate task so a cancelled request can't leave
+                // the entry stuck in the loading state.
+                let state = self.clone();
+                let name = model_name.to_string();
+                let keep_alive = keep_alive.unwrap_or(self.options.keep_alive);
+                tokio::spawn(async move { state.load_embedder(name, keep_alive, tx).await });
+
+                rx
+            }
+        };
+        drop(embedders);
+
+        Self::wait_for_load(rx).await
+    }
+
+    /// Load a model and run a warm-up inference so the first real request
+    /// doesn't pay for lazy initialization.
+    pub async fn warm_up(&self, model_name: &str) -> Result<()> {
+        self.embed(
+            model_name,
+            vec!["warm-up".to_string()],
+            EmbedOptions::default(),
+            None,
+        )
+        .await
+        .map(|_| ())
+    }
+
+    async fn load_embedder(
+        &self,
+        model_name: String,
+        keep_alive: KeepAlive,
+        tx: watch::Sender<LoadResult>,
+    ) {
+        let started = Instant::now();
+        let mut result = self.load_model(&model_name, keep_alive, started).await;
+
+        let mut embedders = self.embedders.write().await;
+        if let Ok(model) = &result {
+            embedders.insert(model_name.clone(), ModelSlot::Loaded(model.clone()));
+            // The estimate made room before loading; this settles any
+            // difference from the loaded size
+            if let Err(e) =
+                self.make_room(&mut embedders, &model_name, model.embedder.memory_bytes())
+            {
+                result = Err(e);
+            }
+        }
+        match &result {
+            Ok(_) => {
+                metrics::MODEL_LOAD_DURATION
+                    .with_label_values(&[&model_name])
+                    .observe(started.elapsed().as_secs_f64());
+                self.clear_error(&model_name);
+            }
+            Err(e) => {
+                tracing::error!("Failed to load model '{}': {}", model_name, e);
+                embedders.remove(&model_name);
+                if !matches!(e, Error::ModelNotFound(_)) {
+                    self.record_error(&model_name, e);
+                }
+            }
+        }
+        drop(embedders);
+
+        let _ = tx.send(Some(result));
+    }
+
+    /// Make room for the model within the configured limits, then load it.
+    async fn load_model(
+        &self,
+        model_name: &str,
+        keep_alive: KeepAlive,
+        started: Instant,
+    ) -> Result<Arc<LoadedModel>> {
+        let config = self.config.clone();
+        let name = model_name.to_string();
+        let mut model_info = self
+            .run_blocking(move || Ok(ModelRegistry::load(&config)?.get_model(&name)?.clone()))
+            .await?;
+        model_info.options = model_info
+            .options
+            .with_defaults(&self.options.model_defaults);
+
+        // Evict before loading, so peak memory stays within the budget
+        let estimate = weights_size(&model_info.model_path);
+        {
+            let mut embedders = self.embedders.write().await;
+            self.make_room(&mut embedders, model_name, estimate)?;
+            if let Some(ModelSlot::Loading { reserved, .. }) = embedders.get_mut(model_name) {
+                *reserved = estimate;
+            }
+        }
+
+        let device = self.device.clone();
+        let name = model_name.to_string();
+        let embedder = self
+            .run_blocking(move || {
+                tracing::info!("Loading model '{}' on device '{:?}'", name, device);
+                Embedder::load(&model_info, device)
+            })
+            .await?;
+
+        let embedder = Arc::new(embedder);
+        Ok(Arc::new(LoadedModel {
+            batcher: Batcher::spawn(self.clone(), embedder.clone(), self.options.batch.clone()),
+            embedder,
+            loaded_at: chrono::Utc::now().to_rfc3339(),
+            load_duration: started.elapsed(),
+            usage: Mutex::new(Usage {
+                last_used: Instant::now(),
+                keep_alive,
+            }),
+        }))
+    }
+
+    async fn wait_for_load(mut rx: watch::Receiver<LoadResult>) -> Result<Arc<LoadedModel>> {
+        let result = rx
+            .wait_for(Option::is_some)
+            .await
+            .map_err(|_| Error::ModelLoadFailed("Model loading was interrupted".to_string()))?;
+
+        result.clone().unwrap_or_else(|| {
+            Err(Error::ModelLoadFailed(
+                "Model loading was interrupted".to_string(),
+            ))
+        })
+    }
+
+    /// Evict least recently used models until `model`, taking `memory_bytes`,
+    /// fits next to the others within `max_loaded_models` and
+    /// `memory_budget`. Models still loading count with their estimated size
+    /// and, like pinned preloads, are never evicted; if what's left doesn't
+    /// leave room, the model is refused rather than going over the limits.
+    fn make_room(
+        &self,
+        embedders: &mut HashMap<String, ModelSlot>,
+        model: &str,
+        memory_bytes: u64,
+    ) -> Result<()> {
+        loop {
+            let others: Vec<(&String, &ModelSlot)> = embedders
+                .iter()
+                .filter(|(name, _)| name.as_str() != model)
+                .collect();
+
+            let memory = memory_bytes
+                + others
+                    .iter()
+                    .map(|(_, slot)| slot.memory_bytes())
+                    .sum::<u64>();
+            let (reason, limit) = match (self.options.max_loaded_models, self.options.memory_budget)
+            {
+                (Some(max), _) if others.len() + 1 > max => ("max_loaded_models", max as u64),
+                (_, Some(budget)) if memory > budget => ("memory_budget", budget),
+                _ => return Ok(()),
+            };
+
+            let victim = others
+                .iter()
+                .filter(|(name, _)| !self.is_pinned(name))
+                .filter_map(|(name, slot)| match slot {
+                    ModelSlot::Loaded(loaded) => Some((*name, loaded)),
+                    ModelSlot::Loading { .. } => None,
+                })
+                .min_by_key(|(_, loaded)| loaded.last_used())
+                .map(|(name, _)| name.clone());
+
+            match victim {
+                Some(name) => {
+                    embedders.remove(&name);
+                    self.record_eviction(&name, reason);
+                }
+                None => {
+                    return Err(Error::ModelLoadFailed(format!(
+                        "Model '{}' ({} bytes) does not fit within {} = {} next to the preloaded and loading models",
+                        model, memory_bytes, reason, limit
+                    )))
+                }
+            }
+        }
+    }
+
+    fn record_eviction(&self, model: &str, reason: &str) {
+        tracing::info!("Evicted model '{}' ({})", model, reason);
+
+        let mut evictions = self.evictions.lock().unwrap();
+        evictions.total += 1;
+        if evictions.recent.len() == MAX_RECENT_EVICTIONS {
+            evictions.recent.pop_front();
+        }
+        evictions.recent.push_back(Eviction {
+            model: model.to_string(),
+            reason: reason.to_string(),
+            evicted_at: chrono::Utc::now().to_rfc3339(),
+        });
+    }
+
+    /// Unload models whose keep_alive has expired, except pinned preloads.
+    async fn evict_idle(&self) {
+        let now = Instant::now();
+        let is_expired = |name: &str, slot: &ModelSlot| match slot {
+            ModelSlot::Loaded(model) => model.is_expired(now) && !self.is_pinned(name),
+            ModelSlot::Loading { .. } => false,
+        };
+
+        if !self
+            .embedders
+            .read()
+            .await
+            .iter()
+            .any(|(name, slot)| is_expired(name, slot))
+        {
+            return;
+        }
+
+        let mut embedders = self.embedders.write().await;
+        let expired: Vec<String> = embedders
+            .iter()
+            .filter(|(name, slot)| is_expired(name, slot))
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in expired {
+            embedders.remove(&name);
+            self.record_eviction(&name, "keep_alive");
+        }
+    }
+
+    /// Start the background task that unloads idle models.
+    pub fn spawn_reaper(&self) -> tokio::task::JoinHandle<()> {
+        let state = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(REAPER_INTERVAL);
+            loop {
+                interval.tick().await;
+                state.evict_idle().await;
+            }
+        })
+    }
+
+    /// Unload a model. In-flight requests keep their reference and finish
+    /// normally; the memory is released once they complete.
+    pub async fn unload_model(&self, model_name: &str) -> Result<()> {
+        let mut embedders = self.embedders.write().await;
+        match embedders.get(model_name) {
+            Some(ModelSlot::Loaded(_)) => {
+                embedders.remove(model_name);
+                // An explicitly unloaded preload no longer holds readiness
+                self.preloaded.lock().unwrap().remove(model_name);
+                tracing::info!("Unloaded model '{}'", model_name);
+                Ok(())
+            }
+            Some(ModelSlot::Loading { .. }) => Err(Error::InvalidInput(format!(
+                "Model '{}' is still loading",
+                model_name
+            ))),
+            None => Err(Error::ModelNotFound(format!("{} (not loaded)", model_name))),
+        }
+    }
+
+    /// Status of every registered model, loaded or not.
+    pub async fn list_models(&self) -> Result<Vec<ModelStatus>> {
+        let config = self.config.clone();
+        let registered = tokio::task::spawn_blocking(move || {
+            let registry = ModelRegistry::load(&config)?;
+            let models: Vec<ModelStatus> = registry
+                .list_models()
+                .into_iter()
+                .map(|info| ModelStatus {
+                    name: info.alias.clone().unwrap_or_else(|| info.name.clone()),
+                    repository: info.hf_repo_id.clone(),
+                    state: ModelState::Unloaded,
+                    device: None,
+                    loaded_at: None,
+                    load_duration_ms: None,
+                    memory_bytes: weights_size(&info.model_path),
+                    last_error: None,
+                    queue_depth: 0,
+                })
+                .collect();
+            Ok::<_, Error>(models)
+        })
+        .await
+        .map_err(|e| Error::Config(format!("Failed to read registry: {}", e)))??;
+
+        let embedders = self.embedders.read().await;
+        let errors = self.errors.lock().unwrap().clone();
+        let mut models: Vec<ModelStatus> = registered
+            .into_iter()
+            .map(|mut status| {
+                status.last_error = errors.get(&status.name).cloned();
+                match embedders.get(&status.name) {
+                    Some(ModelSlot::Loaded(model)) => {
+                        status.state = ModelState::Loaded;
+                        status.device = Some(format!("{:?}", self.device));
+                        status.loaded_at = Some(model.loaded_at.clone());
+                        status.load_duration_ms = Some(model.load_duration.as_millis() as u64);
+                        status.memory_bytes = model.embedder.memory_bytes();
+                        status.queue_depth = model.batcher.queue_depth();
+                    }
+                    Some(ModelSlot::Loading { .. }) => status.state = ModelState::Loading,
+                    None => {}
+                }
+
+                status
+            })
+            .collect();
+
+        models.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(models)
+    }
+
+    pub async fn model_status(&self, model_name: &str) -> Result<ModelStatus> {
+        self.list_models()
+            .await?
+            .into_iter()
+            .find(|status| status.name == model_name)
+            .ok_or_else(|| Error::ModelNotFound(model_name.to_string()))
+    }
+
+    pub async fn loaded_models(&self) -> Vec<String> {
+        let embedders = self.embedders.read().await;
+        embedders
+            .iter()
+            .filter(|(_, slot)| matches!(slot, ModelSlot::Loaded(_)))
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    pub async fn memory_bytes(&self) -> u64 {
+        let embedders = self.embedders.read().await;
+        embedders
+            .values()
+            .filter(|slot| matches!(slot, ModelSlot::Loaded(_)))
+            .map(ModelSlot::memory_bytes)
+            .sum()
+    }
+
+    pub fn eviction_stats(&self) -> EvictionStats {
+        let evictions = self.evictions.lock().unwrap();
+        EvictionStats {
+            total: evictions.total,
+            recent: evictions.recent.iter().cloned().collect(),
+        }
+    }
+
+    /// Run blocking work (model loading, inference) on the blocking thread
+    /// pool, limited to the configured number of workers so that compute
+    /// never stalls the async runtime.
+    pub async fn run_blocking<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let queued = metrics::GaugeGuard::new(&metrics::QUEUE_DEPTH);
+        let _permit = self
+            .workers
+            .acquire()
+            .await
+            .map_err(|e| Error::Embedding(format!("Worker pool closed: {}", e)))?;
+        drop(queued);
+
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(|e| Error::Embedding(format!("Worker task failed: {}", e)))?
+    }
+}
+
+/// Size of a model's weights on disk, as an estimate of the memory it takes
+/// once loaded. Unconverted PyTorch weights are about the size of the
+/// SafeTensors they convert to.
+fn weights_size(model_path: &Path) -> u64 {
+    [SAFETENSORS_FILE, PYTORCH_FILE]
+        .iter()
+        .find_map(|file| std::fs::metadata(model_path.join(file)).ok())
+        .map_or(0, |metadata| metadata.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::testing::{add_model, fixture, weights};
+
+    #[tokio::test]
+    async fn readiness_recovers_after_inference_failure() {
+        let config = fixture("state-readiness");
+        let weights = weights(&config);
+        let options = ServerOptions {
+            preload: vec!["tiny".to_string()],
+            ..Default::default()
+        };
+        let state = AppState::new(config, Device::Cpu, options);
+        state.preload().await.unwrap();
+        assert_eq!(state.readiness().await, Ok(()));
+
+        // Inference reads the weights, so it fails while they're missing
+        let moved = weights.with_extension("moved");
+        std::fs::rename(&weights, &moved).unwrap();
+        let texts = vec!["cat".to_string()];
+        assert!(state
+            .embed("tiny", texts, EmbedOptions::default(), None)
+            .await
+            .is_err());
+        assert!(state.readiness().await.is_err());
+
+        // The canary clears the error without any traffic
+        std::fs::rename(&moved, &weights).unwrap();
+        assert_eq!(state.readiness().await, Ok(()));
+        assert!(state.errors.lock().unwrap().is_empty());
+    }
+
+    /// A fixture with a second model, `other`, sharing `tiny`'s weights.
+    fn two_models(name: &str) -> (Config, u64) {
+        let config = fixture(name);
+        add_model(&config, "other");
+
+        let size = std::fs::metadata(weights(&config)).unwrap().len();
+        (config, size)
+    }
+
+    #[tokio::test]
+    async fn evicts_before_loading_within_memory_budget() {
+        let (config, size) = two_models("state-budget");
+        let options = ServerOptions {
+            memory_budget: Some(size * 3 / 2),
+            ..Default::default()
+        };
+        let state = AppState::new(config, Device::Cpu, options);
+
+        state.get_or_load_embedder("tiny", None).await.unwrap();
+        state.get_or_load_embedder("other", None).await.unwrap();
+        assert_eq!(state.loaded_models().await, vec!["other".to_string()]);
+        assert_eq!(state.eviction_stats().recent[0].model, "tiny");
+        assert!(state.memory_bytes().await <= size * 3 / 2);
+    }
+
+    #[tokio::test]
+    async fn refuses_models_that_do_not_fit() {
+        let (config, size) = two_models("state-refuse");
+        let options = ServerOptions {
+            memory_budget: Some(size * 3 / 2),
+            preload: vec!["tiny".to_string()],
+            ..Default::default()
+        };
+        let state = AppState::new(config.clone(), Device::Cpu, options);
+        state.preload().await.unwrap();
+
+        // The pinned preload leaves no room
+        let error = state.get_or_load_embedder("other", None).await.err();
+        assert!(matches!(error, Some(Error::ModelLoadFailed(_))));
+        assert_eq!(state.loaded_models().await, vec!["tiny".to_string()]);
+
+        // Nor does a budget smaller than the model itself
+        let options = ServerOptions {
+            memory_budget: Some(size / 2),
+            ..Default::default()
+        };
+        let state = AppState::new(config, Device::Cpu, options);
+        let error = state.get_or_load_embedder("tiny", None).await.err();
+        assert!(matches!(error, Some(Error::ModelLoadFailed(_))));
+        assert!(state.loaded_models().await.is_empty());
+    }
+}
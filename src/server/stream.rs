@@ -0,0 +1,436 @@
+use super::auth::ApiKey;
+use super::metrics::ModelLabel;
+use super::{limits, public_error, AppState, KeepAlive};
+use crate::embedder::{EmbedOptions, InputType};
+use crate::error::{Error, Result};
+use crate::model::Pooling;
+use axum::{
+    body::{Body, BodyDataStream, Bytes},
+    extract::{Query, State},
+    http::{header, HeaderValue, Uri},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures_util::{future, stream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+const NDJSON: &str = "application/x-ndjson";
+
+/// Lines embedded together; smaller batches form when the client sends
+/// slower than the model embeds.
+const STREAM_BATCH_INPUTS: usize = 64;
+
+/// Batches submitted to the batching queue ahead of the one being written.
+const STREAM_PIPELINE_DEPTH: usize = 4;
+
+/// Query parameters of `/api/embed/stream`; the same options as
+/// `/api/embed`, applied to every line.
+#[derive(Deserialize)]
+pub struct EmbedStreamParams {
+    pub model: String,
+    #[serde(default)]
+    pub keep_alive: Option<i64>,
+    #[serde(default)]
+    pub input_type: Option<InputType>,
+    #[serde(default)]
+    pub pooling: Option<Pooling>,
+    #[serde(default)]
+    pub normalize: Option<bool>,
+    #[serde(default)]
+    pub max_seq_length: Option<usize>,
+    #[serde(default)]
+    pub dimensions: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct InputLine {
+    #[serde(default)]
+    id: Value,
+    text: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum OutputLine {
+    Embedding { id: Value, embedding: Vec<f32> },
+    Error { id: Value, error: String },
+}
+
+/// An input line, or why it can't be embedded.
+struct Entry {
+    id: Value,
+    text: Result<String>,
+}
+
+/// Embed NDJSON `{"id": ..., "text": ...}` lines, streaming back
+/// `{"id": ..., "embedding": [...]}` lines in input order as each
+/// micro-batch completes. A line that can't be embedded gets
+/// `{"id": ..., "error": ...}` instead, and the stream carries on.
+pub async fn embed_stream_handler(
+    State(state): State<AppState>,
+    Extension(label): Extension<ModelLabel>,
+    key: Option<Extension<ApiKey>>,
+    uri: Uri,
+    body: Body,
+) -> Result<Response> {
+    let Query(params) = Query::<EmbedStreamParams>::try_from_uri(&uri)
+        .map_err(|rejection| Error::InvalidInput(rejection.body_text()))?;
+    tracing::Span::current().record("model", params.model.as_str());
+
+    // Held until the response body is done, not just until this handler
+    // returns, so long streams count against the in-flight limit
+    let permit = limits::acquire_request(&state)?;
+
+    let options = EmbedOptions {
+        input_type: params.input_type,
+        pooling: params.pooling,
+        normalize: params.normalize,
+        max_seq_length: params.max_seq_length,
+        dimensions: params.dimensions,
+    };
+    let keep_alive = params.keep_alive.map(KeepAlive::from_secs);
+
+    // Unknown models and invalid options fail the request up front
+    let embedder = state
+        .get_or_load_embedder(&params.model, keep_alive)
+        .await?;
+    embedder.check_options(&options)?;
+    label.set(&params.model);
+
+    let limits = state.options().limits.clone();
+    let batch_inputs = STREAM_BATCH_INPUTS
+        .min(limits.max_inputs_per_request)
+        .max(1);
+    let model = params.model;
+    let key = key.map(|Extension(key)| key);
+
+    let entries = lines(body.into_data_stream(), limits.max_request_bytes)
+        .take_until({
+            let state = state.clone();
+            async move { state.shutdown_requested().await }
+        })
+        .map(move |line| parse_line(line, limits.max_input_chars));
+
+    // The key was admitted for the first batch when the stream opened; each
+    // later batch is admitted like a request of its own, and a refused batch
+    // ends the stream
+    let batches = entries
+        .ready_chunks(batch_inputs)
+        .enumerate()
+        .map({
+            let state = state.clone();
+            move |(index, batch)| {
+                let state = state.clone();
+                let model = model.clone();
+                let options = options.clone();
+                let key = key.clone();
+                async move {
+                    if let Some(key) = key.as_ref().filter(|_| index > 0) {
+                        if let Err((error, _)) = key.policy.admit(&key.label, Some(&model)) {
+                            return Err(encode_lines(&[OutputLine::Error {
+                                id: Value::Null,
+                                error: public_error(&error),
+                            }]));
+                        }
+                    }
+                    Ok(embed_batch(&state, &model, options, keep_alive, key, batch).await)
+                }
+            }
+        })
+        .buffered(STREAM_PIPELINE_DEPTH)
+        .scan(false, |refused, batch| {
+            let bytes = match batch {
+                _ if *refused => None,
+                Ok(bytes) => Some(bytes),
+                Err(bytes) => {
+                    *refused = true;
+                    Some(bytes)
+                }
+            };
+            future::ready(bytes)
+        });
+
+    let shutdown = stream::once(async move {
+        if state.is_shutting_down() {
+            let error = Error::Overloaded("Server is shutting down".to_string());
+            Some(encode_lines(&[OutputLine::Error {
+                id: Value::Null,
+                error: public_error(&error),
+            }]))
+        } else {
+            None
+        }
+    })
+    .filter_map(|line| async move { line });
+
+    let body = batches.chain(shutdown).map(move |bytes| {
+        let _permit = &permit;
+        Ok::<_, std::convert::Infallible>(bytes)
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, HeaderValue::from_static(NDJSON))],
+        Body::from_stream(body),
+    )
+        .into_response())
+}
+
+async fn embed_batch(
+    state: &AppState,
+    model: &str,
+    options: EmbedOptions,
+    keep_alive: Option<KeepAlive>,
+    key: Option<ApiKey>,
+    batch: Vec<Entry>,
+) -> Bytes {
+    let texts: Vec<String> = batch
+        .iter()
+        .filter_map(|entry| entry.text.as_ref().ok().cloned())
+        .collect();
+
+    let mut embeddings = if texts.is_empty() {
+        Ok(Vec::new().into_iter())
+    } else {
+        state
+            .embed(model, texts, options, keep_alive)
+            .await
+            .map(|result| {
+                if let Some(key) = &key {
+                    key.policy.charge_tokens(result.tokens);
+                }
+                result.embeddings.into_iter()
+            })
+            .map_err(|e| public_error(&e))
+    };
+
+    let lines: Vec<OutputLine> = batch
+        .into_iter()
+        .map(|entry| match (entry.text, &mut embeddings) {
+            (Err(e), _) => OutputLine::Error {
+                id: entry.id,
+                error: public_error(&e),
+            },
+            (Ok(_), Ok(embeddings)) => OutputLine::Embedding {
+                id: entry.id,
+                embedding: embeddings.next().unwrap_or_default(),
+            },
+            (Ok(_), Err(error)) => OutputLine::Error {
+                id: entry.id,
+                error: error.clone(),
+            },
+        })
+        .collect();
+
+    encode_lines(&lines)
+}
+
+fn encode_lines(lines: &[OutputLine]) -> Bytes {
+    let mut bytes = Vec::new();
+    for line in lines {
+        // Values are plain JSON, so serialization can't fail
+        serde_json::to_writer(&mut bytes, line).expect("serializable output line");
+        bytes.push(b'\n');
+    }
+    Bytes::from(bytes)
+}
+
+fn parse_line(line: Result<String>, max_input_chars: usize) -> Entry {
+    let line = match line {
+        Ok(line) => line,
+        Err(e) => {
+            return Entry {
+                id: Value::Null,
+                text: Err(e),
+            }
+        }
+    };
+
+    let input: InputLine = match serde_json::from_str(&line) {
+        Ok(input) => input,
+        Err(e) => {
+            return Entry {
+                id: Value::Null,
+                text: Err(Error::InvalidInput(format!("Failed to parse line: {}", e))),
+            }
+        }
+    };
+
+    let text = match input.text {
+        None => Err(Error::InvalidInput("Missing text".to_string())),
+        Some(text) if text.len() > max_input_chars => {
+            let chars = text.chars().count();
+            if chars > max_input_chars {
+                Err(Error::InvalidInput(format!(
+                    "Input is too long: {} characters (maximum is {})",
+                    chars, max_input_chars
+                )))
+            } else {
+                Ok(text)
+            }
+        }
+        Some(text) => Ok(text),
+    };
+
+    Entry { id: input.id, text }
+}
+
+struct LineReader {
+    body: BodyDataStream,
+    buffer: Vec<u8>,
+    /// Start of the bytes in `buffer` not yet returned as lines
+    start: usize,
+    /// Bytes of `buffer` already searched for a newline
+    scanned: usize,
+    max_bytes: usize,
+    /// Skipping the rest of an overlong line
+    discarding: bool,
+    done: bool,
+}
+
+/// Split a request body into non-empty lines. A line longer than
+/// `max_bytes` (not counting its newline) is skipped and reported as an
+/// error, so it doesn't end the stream.
+fn lines(body: BodyDataStream, max_bytes: usize) -> impl Stream<Item = Result<String>> {
+    let reader = LineReader {
+        body,
+        buffer: Vec::new(),
+        start: 0,
+        scanned: 0,
+        max_bytes,
+        discarding: false,
+        done: false,
+    };
+
+    stream::unfold(reader, |mut reader| async move {
+        loop {
+            let newline = reader.buffer[reader.scanned..]
+                .iter()
+                .position(|&b| b == b'\n')
+                .map(|i| reader.scanned + i);
+
+            let line = match newline {
+                Some(end) => {
+                    let line = reader.start..end;
+                    reader.start = end + 1;
+                    reader.scanned = reader.start;
+                    Some(line)
+                }
+                None if reader.done => {
+                    if reader.start == reader.buffer.len() && !reader.discarding {
+                        return None;
+                    }
+                    let line = reader.start..reader.buffer.len();
+                    reader.start = reader.buffer.len();
+                    reader.scanned = reader.start;
+                    Some(line)
+                }
+                None => {
+                    reader.scanned = reader.buffer.len();
+                    if reader.buffer.len() - reader.start > reader.max_bytes {
+                        reader.discarding = true;
+                        reader.buffer.clear();
+                        reader.start = 0;
+                        reader.scanned = 0;
+                    }
+                    None
+                }
+            };
+
+            if let Some(line) = line {
+                if std::mem::take(&mut reader.discarding) || line.len() > reader.max_bytes {
+                    let error = Error::InvalidInput(format!(
+                        "Line is too long (maximum is {} bytes)",
+                        reader.max_bytes
+                    ));
+                    return Some((Err(error), reader));
+                }
+                match std::str::from_utf8(&reader.buffer[line]) {
+                    Ok(line) if line.trim().is_empty() => continue,
+                    Ok(line) => {
+                        let line = line.to_string();
+                        return Some((Ok(line), reader));
+                    }
+                    Err(_) => {
+                        let error = Error::InvalidInput("Line is not valid UTF-8".to_string());
+                        return Some((Err(error), reader));
+                    }
+                }
+            }
+
+            // Drop the lines already returned once per chunk, rather than
+            // shifting the buffer for every line
+            reader.buffer.drain(..reader.start);
+            reader.scanned -= reader.start;
+            reader.start = 0;
+
+            match reader.body.next().await {
+                Some(Ok(chunk)) => reader.buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    reader.done = true;
+                    reader.buffer.clear();
+                    reader.scanned = 0;
+                    let error = Error::InvalidInput(format!("Failed to read request body: {}", e));
+                    return Some((Err(error), reader));
+                }
+                None => reader.done = true,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn split(chunks: &[&[u8]], max_bytes: usize) -> Vec<std::result::Result<String, String>> {
+        let chunks: Vec<std::result::Result<Bytes, std::convert::Infallible>> = chunks
+            .iter()
+            .map(|chunk| Ok(Bytes::copy_from_slice(chunk)))
+            .collect();
+        let body = Body::from_stream(stream::iter(chunks)).into_data_stream();
+        lines(body, max_bytes)
+            .map(|line| line.map_err(|e| e.to_string()))
+            .collect()
+            .await
+    }
+
+    #[tokio::test]
+    async fn joins_lines_split_across_chunks() {
+        let lines = split(&[b"{\"id\":", b"1}\n{\"id\"", b":2}\n"], 64).await;
+        assert_eq!(
+            lines,
+            [Ok(r#"{"id":1}"#.to_string()), Ok(r#"{"id":2}"#.to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_overlong_lines() {
+        // Within one chunk, and spread over several
+        let lines = split(&[b"0123456789\nshort\n", b"0123", b"456789", b"\nok\n"], 8).await;
+        assert!(lines[0].as_ref().unwrap_err().contains("too long"));
+        assert_eq!(lines[1], Ok("short".to_string()));
+        assert!(lines[2].as_ref().unwrap_err().contains("too long"));
+        assert_eq!(lines[3], Ok("ok".to_string()));
+        assert_eq!(lines.len(), 4);
+
+        // The newline doesn't count towards the limit
+        assert_eq!(
+            split(&[b"12345678\n"], 8).await,
+            [Ok("12345678".to_string())]
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_final_line_without_newline() {
+        let lines = split(&[b"one\n\n", b"two"], 64).await;
+        assert_eq!(lines, [Ok("one".to_string()), Ok("two".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn reports_invalid_utf8() {
+        let lines = split(&[b"\xff\xfe\n", b"ok\n"], 64).await;
+        assert!(lines[0].as_ref().unwrap_err().contains("UTF-8"));
+        assert_eq!(lines[1], Ok("ok".to_string()));
+    }
+}
@@ -0,0 +1,68 @@
+use crate::config::Config;
+use crate::model::{ModelInfo, ModelRegistry};
+use candle_core::{Device, Tensor};
+use std::path::PathBuf;
+
+/// Vocabulary of the fixture model. The last word has no embedding row, so
+/// inputs containing it fail at inference.
+pub const VOCAB: &[&str] = &[
+    "[PAD]", "[UNK]", "[CLS]", "[SEP]", "cat", "dog", "car", "broken",
+];
+
+/// A data directory holding a model `tiny` whose token embeddings are
+/// one-hot, so "cat" and "dog" are orthogonal to each other.
+pub fn fixture(name: &str) -> Config {
+    let data_dir = std::env::temp_dir().join(format!("embeddy-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    let model_path = data_dir.join("models").join("tiny");
+    std::fs::create_dir_all(&model_path).unwrap();
+
+    std::fs::write(model_path.join("config.json"), r#"{"hidden_size": 8}"#).unwrap();
+    std::fs::write(model_path.join("vocab.txt"), VOCAB.join("\n")).unwrap();
+    let weights = Tensor::eye(8, candle_core::DType::F32, &Device::Cpu)
+        .and_then(|eye| eye.narrow(0, 0, VOCAB.len() - 1))
+        .unwrap();
+    candle_core::safetensors::save(
+        &[("embeddings.word_embeddings.weight".to_string(), weights)]
+            .into_iter()
+            .collect(),
+        model_path.join(crate::model::convert::SAFETENSORS_FILE),
+    )
+    .unwrap();
+
+    let config = Config {
+        models_dir: data_dir.join("models"),
+        registry_path: data_dir.join("models.toml"),
+        config_path: data_dir.join("config.toml"),
+        data_dir,
+    };
+    let mut registry = ModelRegistry::default();
+    registry.add_model(ModelInfo {
+        name: "tiny".to_string(),
+        hf_repo_id: "test/tiny".to_string(),
+        alias: None,
+        model_path,
+        embedding_dim: Some(8),
+        downloaded_at: "2025-01-01T00:00:00Z".to_string(),
+        options: Default::default(),
+    });
+    registry.save(&config).unwrap();
+    config
+}
+
+/// Register another model sharing the fixture model's files.
+pub fn add_model(config: &Config, name: &str) {
+    let mut registry = ModelRegistry::load(config).unwrap();
+    let mut model = registry.get_model("tiny").unwrap().clone();
+    model.name = name.to_string();
+    registry.add_model(model);
+    registry.save(config).unwrap();
+}
+
+/// Weights file of the fixture model.
+pub fn weights(config: &Config) -> PathBuf {
+    config
+        .models_dir
+        .join("tiny")
+        .join(crate::model::convert::SAFETENSORS_FILE)
+}
@@ -0,0 +1,131 @@
+use crate::error::{Error, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+/// How often certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct TlsOptions {
+    /// PEM certificate chain, leaf first.
+    pub cert: PathBuf,
+    /// PEM private key (PKCS#8, PKCS#1 or SEC1).
+    pub key: PathBuf,
+    /// PEM bundle of CAs client certificates must chain to; enables mTLS.
+    pub client_ca: Option<PathBuf>,
+}
+
+impl TlsOptions {
+    fn files(&self) -> Vec<&Path> {
+        let mut files = vec![self.cert.as_path(), self.key.as_path()];
+        files.extend(self.client_ca.as_deref());
+        files
+    }
+
+    /// Build the rustls configuration from the files on disk.
+    pub fn server_config(&self) -> Result<ServerConfig> {
+        let provider = Arc::new(ring::default_provider());
+
+        let certs = CertificateDer::pem_file_iter(&self.cert)
+            .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+            .map_err(|e| pem_error(&self.cert, e))?;
+        if certs.is_empty() {
+            return Err(Error::Config(format!(
+                "No certificates found in {:?}",
+                self.cert
+            )));
+        }
+        let key = PrivateKeyDer::from_pem_file(&self.key).map_err(|e| pem_error(&self.key, e))?;
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| Error::Config(format!("Invalid TLS configuration: {}", e)))?;
+
+        let builder = match &self.client_ca {
+            Some(ca) => builder.with_client_cert_verifier(client_verifier(ca, provider)?),
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| Error::Config(format!("Invalid certificate or key: {}", e)))?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(config)
+    }
+
+    pub fn rustls_config(&self) -> Result<RustlsConfig> {
+        Ok(RustlsConfig::from_config(Arc::new(self.server_config()?)))
+    }
+
+    /// Watch the certificate, key and CA files and swap in a new
+    /// configuration when any of them changes. Connections already
+    /// established keep the configuration they were accepted with; a
+    /// configuration that fails to load is logged and the current one kept.
+    pub fn spawn_reloader(&self, config: RustlsConfig) -> tokio::task::JoinHandle<()> {
+        let options = self.clone();
+        tokio::spawn(async move {
+            let mut seen = options.modified();
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+            interval.tick().await;
+
+            loop {
+                interval.tick().await;
+
+                let modified = options.modified();
+                if modified == seen {
+                    continue;
+                }
+                seen = modified;
+
+                match options.server_config() {
+                    Ok(server_config) => {
+                        config.reload_from_config(Arc::new(server_config));
+                        tracing::info!("Reloaded TLS certificate from {:?}", options.cert);
+                    }
+                    Err(e) => tracing::error!("Failed to reload TLS certificate: {}", e),
+                }
+            }
+        })
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files()
+            .into_iter()
+            .map(|file| std::fs::metadata(file).and_then(|m| m.modified()).ok())
+            .collect()
+    }
+}
+
+fn client_verifier(
+    ca: &Path,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(ca).map_err(|e| pem_error(ca, e))? {
+        roots
+            .add(cert.map_err(|e| pem_error(ca, e))?)
+            .map_err(|e| Error::Config(format!("Invalid CA certificate in {:?}: {}", ca, e)))?;
+    }
+    if roots.is_empty() {
+        return Err(Error::Config(format!(
+            "No CA certificates found in {:?}",
+            ca
+        )));
+    }
+
+    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+        .build()
+        .map_err(|e| Error::Config(format!("Invalid client CA bundle {:?}: {}", ca, e)))
+}
+
+fn pem_error(path: &Path, e: rustls::pki_types::pem::Error) -> Error {
+    Error::Config(format!("Failed to read PEM file {:?}: {}", path, e))
+}
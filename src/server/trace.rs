@@ -0,0 +1,98 @@
+use crate::error::{Error, Result};
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderName, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tower_http::request_id::RequestId;
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Message of an error response, attached by `IntoResponse for Error` so the
+/// envelope can be rebuilt with the request ID.
+#[derive(Debug, Clone)]
+pub struct ErrorMessage(pub String);
+
+/// Span for each request. `model`, `inputs` and `key` are filled in by the
+/// handlers and the auth middleware once they are known.
+pub fn make_span(request: &Request) -> tracing::Span {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default();
+
+    tracing::info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        request_id,
+        model = tracing::field::Empty,
+        inputs = tracing::field::Empty,
+        key = tracing::field::Empty,
+    )
+}
+
+/// Middleware adding the request ID to error envelopes, so a client can
+/// quote it when reporting a failure.
+pub async fn request_id_in_errors(request: Request, next: Next) -> Response {
+    let request_id = request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(str::to_string);
+
+    let response = next.run(request).await;
+
+    let (Some(request_id), Some(ErrorMessage(message))) = (
+        request_id,
+        response.extensions().get::<ErrorMessage>().cloned(),
+    ) else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    let body = serde_json::json!({
+        "error": message,
+        "request_id": request_id,
+    });
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body.to_string()))
+}
+
+/// CORS layer allowing the given origins, or any origin for `*`. Returns
+/// `None` when no origins are configured.
+pub fn cors_layer(origins: &[String]) -> Result<Option<CorsLayer>> {
+    if origins.is_empty() {
+        return Ok(None);
+    }
+
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins = origins
+            .iter()
+            .map(|origin| {
+                HeaderValue::from_str(origin.trim_end_matches('/'))
+                    .map_err(|_| Error::Config(format!("Invalid CORS origin: {}", origin)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        AllowOrigin::list(origins)
+    };
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                header::ACCEPT,
+                REQUEST_ID_HEADER,
+            ])
+            .expose_headers([REQUEST_ID_HEADER, header::RETRY_AFTER]),
+    ))
+}
@@ -0,0 +1,237 @@
+use super::auth::ApiKey;
+use super::{limits, public_error, AppState, KeepAlive};
+use crate::embedder::encoding::{EncodedEmbeddings, Encoding};
+use crate::embedder::EmbedOptions;
+use crate::error::{Error, Result};
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+    Extension,
+};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tokio::task::{AbortHandle, JoinSet};
+
+/// Requests from one connection processed concurrently; further frames
+/// aren't read until one finishes.
+const WS_CONCURRENCY: usize = 32;
+
+#[derive(Deserialize)]
+struct WsRequest {
+    id: Value,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    input: Vec<String>,
+    #[serde(default)]
+    keep_alive: Option<i64>,
+    /// Cancel the request with this `id` without starting a new one
+    #[serde(default)]
+    cancel: bool,
+    #[serde(flatten)]
+    options: EmbedOptions,
+    #[serde(flatten)]
+    encoding: Encoding,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum WsResponse {
+    Embeddings {
+        id: Value,
+        embeddings: EncodedEmbeddings,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        int8_range: Option<[f32; 2]>,
+    },
+    Error {
+        id: Value,
+        error: String,
+    },
+}
+
+/// Upgrade to a WebSocket carrying `{"id", "model", "input"}` request frames
+/// and `{"id", "embeddings"}` response frames. Authentication and key
+/// policy apply to the upgrade request like any other route; each frame is
+/// then admitted as its own request.
+pub async fn ws_handler(
+    State(state): State<AppState>,
+    key: Option<Extension<ApiKey>>,
+    upgrade: WebSocketUpgrade,
+) -> Response {
+    let key = key.map(|Extension(key)| key);
+    upgrade
+        .max_message_size(state.options().limits.max_request_bytes)
+        .on_upgrade(move |socket| session(state, key, socket))
+}
+
+async fn session(state: AppState, key: Option<ApiKey>, socket: WebSocket) {
+    let _session = state.open_session();
+    let (mut sink, mut frames) = socket.split();
+    let (tx, mut rx) = mpsc::channel::<Message>(WS_CONCURRENCY);
+
+    let writer = tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    // Running requests by ID, so a new request with the same ID can cancel
+    // the one it supersedes
+    let mut tasks = JoinSet::new();
+    let mut running: HashMap<String, AbortHandle> = HashMap::new();
+    let mut shutting_down = false;
+
+    loop {
+        tokio::select! {
+            Some(done) = tasks.join_next_with_id(), if !tasks.is_empty() => {
+                // Aborted tasks were already replaced or removed
+                if let Ok((task, request_key)) = done {
+                    if running.get(&request_key).is_some_and(|handle| handle.id() == task) {
+                        running.remove(&request_key);
+                    }
+                }
+            }
+            frame = frames.next(), if tasks.len() < WS_CONCURRENCY => {
+                let text = match frame {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Binary(_))) => {
+                        let error = Error::InvalidInput("Frames must be JSON text".to_string());
+                        let _ = tx.send(reply(error_response(Value::Null, &error))).await;
+                        continue;
+                    }
+                    // Pings are answered by axum
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => continue,
+                    Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                };
+
+                let request = match parse_request(text.as_str()) {
+                    Ok(request) => request,
+                    Err((id, error)) => {
+                        let _ = tx.send(reply(error_response(id, &error))).await;
+                        continue;
+                    }
+                };
+
+                let request_key = request.id.to_string();
+                if let Some(superseded) = running.remove(&request_key) {
+                    superseded.abort();
+                }
+                if request.cancel {
+                    continue;
+                }
+
+                let state = state.clone();
+                let key = key.clone();
+                let tx = tx.clone();
+                let task_key = request_key.clone();
+                let handle = tasks.spawn(async move {
+                    let id = request.id.clone();
+                    let response = embed(&state, key.as_ref(), request)
+                        .await
+                        .unwrap_or_else(|e| error_response(id, &e));
+                    let _ = tx.send(reply(response)).await;
+                    task_key
+                });
+                running.insert(request_key, handle);
+            }
+            _ = state.shutdown_requested(), if !shutting_down => {
+                shutting_down = true;
+                break;
+            }
+        }
+    }
+
+    if shutting_down {
+        // Let running requests finish, then tell the client to reconnect
+        // elsewhere
+        while tasks.join_next().await.is_some() {}
+        let _ = tx
+            .send(Message::Close(Some(CloseFrame {
+                code: close_code::AWAY,
+                reason: "Server is shutting down".into(),
+            })))
+            .await;
+    } else {
+        // The client is gone; stop its queued and running work
+        tasks.abort_all();
+    }
+
+    drop(tx);
+    let _ = writer.await;
+}
+
+/// Parse a request frame, keeping its `id` for the error reply when the
+/// rest of the frame is invalid.
+fn parse_request(text: &str) -> std::result::Result<WsRequest, (Value, Error)> {
+    let frame: Value = serde_json::from_str(text).map_err(|e| {
+        (
+            Value::Null,
+            Error::InvalidInput(format!("Failed to parse frame: {}", e)),
+        )
+    })?;
+    let id = frame.get("id").cloned().unwrap_or(Value::Null);
+    if id.is_null() {
+        return Err((id, Error::InvalidInput("Missing id".to_string())));
+    }
+
+    let request = WsRequest::deserialize(frame)
+        .map_err(|e| (id.clone(), Error::InvalidInput(e.to_string())))?;
+    if !request.cancel && request.model.is_none() {
+        return Err((id, Error::InvalidInput("Missing model".to_string())));
+    }
+    Ok(request)
+}
+
+/// Admit and run one request frame, as `/api/embed` would.
+async fn embed(state: &AppState, key: Option<&ApiKey>, request: WsRequest) -> Result<WsResponse> {
+    let model = request.model.unwrap_or_default();
+    // Take the in-flight slot first, so a frame turned away as overloaded
+    // doesn't use up the key's rate limit
+    let _permit = limits::acquire_request(state)?;
+    if let Some(key) = key {
+        key.policy
+            .admit(&key.label, Some(&model))
+            .map_err(|(error, _)| error)?;
+    }
+
+    state.options().limits.check_inputs(&request.input)?;
+    request.encoding.check()?;
+
+    let keep_alive = request.keep_alive.map(KeepAlive::from_secs);
+    let result = state
+        .embed(&model, request.input, request.options, keep_alive)
+        .await?;
+    if let Some(key) = key {
+        key.policy.charge_tokens(result.tokens);
+    }
+
+    let encoded = request
+        .encoding
+        .apply(result.embeddings, result.int8_range)?;
+    Ok(WsResponse::Embeddings {
+        id: request.id,
+        embeddings: encoded.embeddings,
+        int8_range: encoded.int8_range,
+    })
+}
+
+fn error_response(id: Value, error: &Error) -> WsResponse {
+    WsResponse::Error {
+        id,
+        error: public_error(error),
+    }
+}
+
+fn reply(response: WsResponse) -> Message {
+    // Values are plain JSON, so serialization can't fail
+    let text = serde_json::to_string(&response).expect("serializable response");
+    Message::Text(text.into())
+}